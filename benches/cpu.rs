@@ -0,0 +1,92 @@
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use intel8080::asm::assemble;
+use intel8080::cpu::CPU;
+use std::fs;
+
+/// 65536 passes through a MOV/ALU heavy inner loop.
+const ALU_LOOP: &str = "
+        MVI D, 0
+outer:  MVI B, 0
+inner:  MOV A, B
+        ADD C
+        XRA D
+        ANA E
+        ORA H
+        SUB L
+        CMP B
+        MOV C, A
+        MOV E, B
+        DCR B
+        JNZ inner
+        DCR D
+        JNZ outer
+        HLT
+";
+
+fn run_until_halt(cpu: &mut CPU) {
+    while !cpu.is_halted() {
+        cpu.run_once();
+    }
+}
+
+/// Reports each bench as instructions per second as well as time per run.
+fn instructions<'a>(c: &'a mut Criterion, name: &str, cpu: &CPU) -> BenchmarkGroup<'a, WallTime> {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(cpu.instruction_count()));
+    group
+}
+
+fn alu_loop(c: &mut Criterion) {
+    let code = assemble(ALU_LOOP).unwrap();
+    let mut data = vec![0; 65536];
+    data[..code.len()].copy_from_slice(&code);
+
+    let mut cpu = CPU::new(data.clone());
+    run_until_halt(&mut cpu);
+    instructions(c, "alu_loop", &cpu).bench_function("run", |b| {
+        b.iter(|| run_until_halt(&mut CPU::new(data.clone())))
+    });
+}
+
+/// The same loop with `set_update_flags(false)`; it only branches on `DCR`,
+/// so it still runs the same instructions.
+fn alu_loop_without_flags(c: &mut Criterion) {
+    let code = assemble(ALU_LOOP).unwrap();
+    let mut data = vec![0; 65536];
+    data[..code.len()].copy_from_slice(&code);
+
+    let mut cpu = CPU::new(data.clone());
+    run_until_halt(&mut cpu);
+    instructions(c, "alu_loop_without_flags", &cpu).bench_function("run", |b| {
+        b.iter(|| {
+            let mut cpu = CPU::new(data.clone());
+            cpu.set_update_flags(false);
+            run_until_halt(&mut cpu)
+        })
+    });
+}
+
+/// Runs `rom/TST8080.COM` under the same minimal CP/M setup as `run_rom`.
+/// Panics if the ROM is missing, see `rom/README.md`.
+fn tst8080(c: &mut Criterion) {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/rom/TST8080.COM");
+    let rom =
+        fs::read(path).unwrap_or_else(|e| panic!("can't read {}: {}; see rom/README.md", path, e));
+    let mut data = vec![0; 65536];
+    data[0x0100..0x0100 + rom.len()].copy_from_slice(&rom);
+    data[0x0005] = 0xC9;
+
+    let run = |data: &[u8]| {
+        let mut cpu = CPU::new(data.to_vec());
+        cpu.set_pc(0x0100);
+        while !cpu.is_halted() && cpu.pc() != 0 {
+            cpu.run_once();
+        }
+        cpu
+    };
+    instructions(c, "tst8080", &run(&data)).bench_function("run", |b| b.iter(|| run(&data)));
+}
+
+criterion_group!(benches, alu_loop, alu_loop_without_flags, tst8080);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Echoes stdin to stdout through an 8080 program.
+//!
+//! `echo hello | cargo run --example console`
+
+use intel8080::asm::assemble;
+use intel8080::cpu::{Device, CPU};
+use intel8080::device::ConsoleInput;
+use std::io::{self, Stdin, Write};
+
+const ECHO: &str = "
+        LOOP:   IN 0        ; next byte from stdin, 0 at the end
+                CPI 0
+                JZ DONE
+                OUT 1
+                JMP LOOP
+        DONE:   HLT
+";
+
+/// Reads stdin on port 0 and writes stdout on port 1.
+struct Console {
+    input: ConsoleInput<Stdin>,
+}
+
+impl Device for Console {
+    fn input(&mut self, port: u8) -> u8 {
+        self.input.input(port)
+    }
+
+    fn output(&mut self, port: u8, data: u8) {
+        if port == 1 {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[data]).and_then(|_| stdout.flush());
+        }
+    }
+}
+
+fn main() {
+    let code = assemble(ECHO).expect("ECHO assembles");
+    let mut data = vec![0; 0x0100];
+    data[..code.len()].copy_from_slice(&code);
+
+    let mut cpu = CPU::new(data);
+    cpu.set_device(Box::new(Console {
+        input: ConsoleInput::blocking(io::stdin(), 0),
+    }));
+    while !cpu.is_halted() {
+        cpu.run_once();
+    }
+}
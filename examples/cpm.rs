@@ -0,0 +1,36 @@
+//! Runs a CP/M `.COM` program that only uses BDOS console output, such as
+//! the 8080 diagnostics.
+//!
+//! `cargo run --example cpm -- rom/TST8080.COM`
+
+use intel8080::cpm::{CpmBdos, BDOS_ADDR};
+use intel8080::cpu::CPU;
+use std::env;
+use std::io;
+use std::ops::ControlFlow;
+
+fn main() -> io::Result<()> {
+    let path = env::args().nth(1).expect("usage: cpm <program.COM>");
+    // .COM programs are loaded at 0x0100; the BDOS entry just returns
+    let mut cpu = CPU::from_rom_file(path, 0x10000, 0x0100)?;
+    cpu.set_value(BDOS_ADDR as usize, 0xC9);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut bdos = CpmBdos::new(&mut stdout);
+    let mut result = Ok(());
+    cpu.run_with(|cpu| match cpu.pc() {
+        // jumping to 0 is a warm boot, i.e. the program has finished
+        0x0000 => ControlFlow::Break(()),
+        pc if pc == BDOS_ADDR as usize => match bdos.call(cpu) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(e) => {
+                result = Err(e);
+                ControlFlow::Break(())
+            }
+        },
+        _ => ControlFlow::Continue(()),
+    });
+    println!();
+    result
+}
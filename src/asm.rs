@@ -0,0 +1,342 @@
+//! A small two-pass assembler for 8080 source text.
+//!
+//! Each line has the form `[label:] [MNEMONIC [operand[, operand]]] [; comment]`.
+//! Numbers may be decimal (`10`), hexadecimal with a `0x`/`$` prefix or an `H`
+//! suffix (`0x0A`, `$0A`, `0AH`), or a quoted character (`'A'`). Wherever a
+//! number is expected a label may be used instead, before or after its definition.
+//!
+//! Besides the instructions, the directives `ORG addr`, `DB byte|"text", ...`
+//! and `DW word, ...` are understood.
+
+use crate::error::{Error, Result};
+use crate::opcode::{encode, Operand};
+use crate::register::Register;
+use std::collections::HashMap;
+
+/// Assembles `source` into machine code.
+///
+/// The returned bytes start at the first `ORG` address (0 if there is none).
+pub fn assemble(source: &str) -> Result<Vec<u8>> {
+    assemble_with_symbols(source).map(|(code, _)| code)
+}
+
+/// Like `assemble`, but also returns the address of every label.
+pub fn assemble_with_symbols(source: &str) -> Result<(Vec<u8>, HashMap<String, u16>)> {
+    let lines = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_line(i + 1, text))
+        .collect::<Result<Vec<_>>>()?;
+
+    // first pass: lay out the statements to find the address of every label
+    let mut symbols = HashMap::new();
+    let mut addr = 0u16;
+    for line in &lines {
+        if let Some(Statement::Org(expr)) = line.statement {
+            addr = value(expr, &symbols, true, line.number)?;
+        }
+        if let Some(label) = line.label {
+            if symbols.insert(label.to_string(), addr).is_some() {
+                return Err(Error::DuplicateLabel(label.to_string()));
+            }
+        }
+        if let Some(ref statement) = line.statement {
+            let len = emit(statement, &symbols, false, line.number)?.len();
+            addr = addr.wrapping_add(len as u16);
+        }
+    }
+
+    // second pass: every label is known, so emit the final bytes
+    let mut origin = None;
+    let mut code = Vec::new();
+    let mut addr = 0u16;
+    for line in &lines {
+        let statement = match line.statement {
+            Some(Statement::Org(expr)) => {
+                addr = value(expr, &symbols, true, line.number)?;
+                continue;
+            }
+            Some(ref statement) => statement,
+            None => continue,
+        };
+        let bytes = emit(statement, &symbols, true, line.number)?;
+        let base = *origin.get_or_insert(addr);
+        if addr < base {
+            return Err(Error::Syntax { line: line.number });
+        }
+        let start = usize::from(addr - base);
+        if code.len() < start + bytes.len() {
+            code.resize(start + bytes.len(), 0);
+        }
+        code[start..start + bytes.len()].copy_from_slice(&bytes);
+        addr = addr.wrapping_add(bytes.len() as u16);
+    }
+
+    Ok((code, symbols))
+}
+
+struct Line<'a> {
+    number: usize,
+    label: Option<&'a str>,
+    statement: Option<Statement<'a>>,
+}
+
+enum Statement<'a> {
+    Org(&'a str),
+    Db(Vec<&'a str>),
+    Dw(Vec<&'a str>),
+    Instruction(String, Vec<&'a str>),
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line<'_>> {
+    let mut text = text[..unquoted_find(text, ';').unwrap_or(text.len())].trim();
+
+    let mut label = None;
+    if let Some(i) = unquoted_find(text, ':') {
+        let name = text[..i].trim();
+        if !is_identifier(name) {
+            return Err(Error::Syntax { line: number });
+        }
+        label = Some(name);
+        text = text[i + 1..].trim();
+    }
+
+    if text.is_empty() {
+        return Ok(Line {
+            number,
+            label,
+            statement: None,
+        });
+    }
+
+    let (mnemonic, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    };
+    let operands = split_operands(rest);
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let statement = match mnemonic.as_str() {
+        "ORG" if operands.len() == 1 => Statement::Org(operands[0]),
+        "ORG" => return Err(Error::Syntax { line: number }),
+        "DB" => Statement::Db(operands),
+        "DW" => Statement::Dw(operands),
+        _ => Statement::Instruction(mnemonic, operands),
+    };
+
+    Ok(Line {
+        number,
+        label,
+        statement: Some(statement),
+    })
+}
+
+/// Finds `pat` in `text`, skipping over quoted strings.
+fn unquoted_find(text: &str, pat: char) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == pat => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+fn split_operands(mut text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    if text.is_empty() {
+        return operands;
+    }
+    while let Some(i) = unquoted_find(text, ',') {
+        operands.push(text[..i].trim());
+        text = &text[i + 1..];
+    }
+    operands.push(text.trim());
+    operands
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Evaluates a number or label. Unknown labels are an error only when `strict`,
+/// otherwise they evaluate to 0 so that the first pass can size forward references.
+fn value(expr: &str, symbols: &HashMap<String, u16>, strict: bool, line: usize) -> Result<u16> {
+    let bytes = expr.as_bytes();
+    let hex = expr
+        .strip_prefix("0x")
+        .or_else(|| expr.strip_prefix("0X"))
+        .or_else(|| expr.strip_prefix('$'));
+    let parsed = if bytes.len() == 3 && bytes[0] == b'\'' && bytes[2] == b'\'' {
+        Ok(u16::from(bytes[1]))
+    } else if let Some(digits) = hex {
+        u16::from_str_radix(digits, 16)
+    } else if (expr.ends_with('h') || expr.ends_with('H'))
+        && expr.starts_with(|c: char| c.is_ascii_digit())
+    {
+        u16::from_str_radix(&expr[..expr.len() - 1], 16)
+    } else if expr.starts_with(|c: char| c.is_ascii_digit()) {
+        expr.parse()
+    } else if is_identifier(expr) {
+        return match symbols.get(expr) {
+            Some(&addr) => Ok(addr),
+            None if strict => Err(Error::UndefinedLabel(expr.to_string())),
+            None => Ok(0),
+        };
+    } else {
+        return Err(Error::Syntax { line });
+    };
+    parsed.map_err(|_| Error::Syntax { line })
+}
+
+fn operand(
+    text: &str,
+    symbols: &HashMap<String, u16>,
+    strict: bool,
+    line: usize,
+) -> Result<Operand> {
+    match text.to_ascii_uppercase().as_str() {
+        "SP" => Ok(Operand::Sp),
+        "PSW" => Ok(Operand::Psw),
+        _ => match text.parse::<Register>() {
+            Ok(reg) => Ok(Operand::Reg(reg)),
+            Err(_) => value(text, symbols, strict, line).map(Operand::Imm),
+        },
+    }
+}
+
+fn emit(
+    statement: &Statement,
+    symbols: &HashMap<String, u16>,
+    strict: bool,
+    line: usize,
+) -> Result<Vec<u8>> {
+    let syntax = || Error::Syntax { line };
+    match statement {
+        Statement::Org(_) => Ok(Vec::new()),
+        Statement::Db(items) => {
+            let mut bytes = Vec::new();
+            for item in items {
+                let quoted = item.len() >= 2
+                    && (item.starts_with('"') && item.ends_with('"')
+                        || item.starts_with('\'') && item.ends_with('\''));
+                if quoted && item.len() != 3 {
+                    bytes.extend_from_slice(&item.as_bytes()[1..item.len() - 1]);
+                } else {
+                    let val = value(item, symbols, strict, line)?;
+                    if val > 0xFF {
+                        return Err(syntax());
+                    }
+                    bytes.push(val as u8);
+                }
+            }
+            Ok(bytes)
+        }
+        Statement::Dw(items) => {
+            let mut bytes = Vec::new();
+            for item in items {
+                let val = value(item, symbols, strict, line)?;
+                bytes.push(val as u8);
+                bytes.push((val >> 8) as u8);
+            }
+            Ok(bytes)
+        }
+        Statement::Instruction(mnemonic, texts) => {
+            let operands = texts
+                .iter()
+                .map(|text| operand(text, symbols, strict, line))
+                .collect::<Result<Vec<_>>>()?;
+            encode(mnemonic, &operands).map_err(|_| syntax())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_labels() {
+        let source = "
+                ORG 0x0100
+        start:  MVI B, 3
+        loop:   DCR B       ; count down
+                JNZ loop
+                JMP done
+        done:   HLT
+        ";
+        let (code, symbols) = assemble_with_symbols(source).unwrap();
+
+        assert_eq!(
+            code,
+            vec![0x06, 0x03, 0x05, 0xC2, 0x02, 0x01, 0xC3, 0x09, 0x01, 0x76]
+        );
+        assert_eq!(symbols["start"], 0x0100);
+        assert_eq!(symbols["loop"], 0x0102);
+        assert_eq!(symbols["done"], 0x0109);
+
+        let mut data = vec![0; 0x0200];
+        data[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(data);
+        cpu.set_pc(0x0100);
+        while !cpu.is_halted() {
+            cpu.run_once();
+        }
+        assert_eq!(cpu.registers[Register::B as usize], 0);
+    }
+
+    #[test]
+    fn test_operands() {
+        let source = "
+            MOV A, M
+            mvi m, 'x'
+            LXI SP, 0FFFFH
+            PUSH PSW
+            RST 7
+            RNZ
+            OUT $10
+            DB 1, \"a;b\", 2
+            DW 0x1234
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x7E, 0x36, b'x', 0x31, 0xFF, 0xFF, 0xF5, 0xFF, 0xC0, 0xD3, 0x10, 1, b'a', b';',
+                b'b', 2, 0x34, 0x12,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(match assemble("JMP nowhere") {
+            Err(Error::UndefinedLabel(label)) => label == "nowhere",
+            _ => false,
+        });
+        assert!(match assemble("a: NOP\na: NOP") {
+            Err(Error::DuplicateLabel(label)) => label == "a",
+            _ => false,
+        });
+        assert!(match assemble("NOP\nMOV M, M") {
+            Err(Error::Syntax { line }) => line == 2,
+            _ => false,
+        });
+        assert!(assemble("MVI A, 0x100").is_err());
+        assert!(assemble("PUSH SP").is_err());
+        assert!(match assemble("NOP\né") {
+            Err(Error::Syntax { line }) => line == 2,
+            _ => false,
+        });
+        assert!(assemble("Jé 0").is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Just enough of the CP/M BDOS to run the usual 8080 diagnostic programs.
+//!
+//! Programs call the BDOS with `CALL 5`, the function number in C and its
+//! argument in E or DE. Put a `RET` at address 5 and hand the CPU to
+//! `CpmBdos::call` whenever it reaches that address, or let `run_cpm` do it.
+
+use crate::cpu::CPU;
+use crate::error::Result;
+use crate::register::Register;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+
+/// The BDOS entry point.
+pub const BDOS_ADDR: u16 = 0x0005;
+
+/// Jumping here ends the program (a warm boot).
+pub const WBOOT_ADDR: u16 = 0x0000;
+
+/// Where CP/M loads `.COM` programs.
+pub const TPA_ADDR: u16 = 0x0100;
+
+/// Output that marks a diagnostic program as passed or failed. Failures are
+/// matched as whole words, case-sensitively, so a banner mentioning e.g.
+/// "error-free" doesn't count.
+const PASS_MARKERS: [&str; 4] = [
+    "CPU IS OPERATIONAL",
+    "Preliminary tests complete",
+    "CPU TESTS OK",
+    "Tests complete",
+];
+const FAIL_MARKERS: [&str; 2] = ["FAILED", "ERROR"];
+
+/// How many instructions `run_diagnostic` lets a program run, well above
+/// what the diagnostics need, so a core bug that makes one loop forever
+/// fails instead of hanging.
+pub const DIAGNOSTIC_INSTRUCTION_LIMIT: u64 = 10_000_000_000;
+
+/// What `run_diagnostic` saw.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticResult {
+    pub output: String,
+    pub passed: bool,
+    pub instructions: u64,
+    /// Whether the program was stopped at the instruction limit.
+    pub hit_limit: bool,
+}
+
+/// Runs the `.COM` program `rom` in 64 KiB of memory, sending its console
+/// output to `out`, until it jumps to `wboot_addr` or halts. BDOS calls go to
+/// `bdos_addr`; CP/M itself uses `BDOS_ADDR` and `WBOOT_ADDR`.
+///
+/// Returns the CPU so its final state can be inspected.
+pub fn run_cpm(rom: &[u8], out: &mut dyn Write, bdos_addr: u16, wboot_addr: u16) -> Result<CPU> {
+    run_cpm_limited(rom, out, bdos_addr, wboot_addr, u64::MAX)
+}
+
+/// `run_cpm` that also stops once `limit` instructions have run.
+fn run_cpm_limited(
+    rom: &[u8],
+    out: &mut dyn Write,
+    bdos_addr: u16,
+    wboot_addr: u16,
+    limit: u64,
+) -> Result<CPU> {
+    let mut cpu = CPU::default();
+    cpu.load_at(usize::from(TPA_ADDR), rom)?;
+    cpu.set_value(usize::from(bdos_addr), 0xC9);
+    cpu.set_pc(TPA_ADDR);
+
+    let mut bdos = CpmBdos::new(out);
+    let mut result = Ok(());
+    cpu.run_with(|cpu| match cpu.pc() as u16 {
+        _ if cpu.instruction_count() >= limit => ControlFlow::Break(()),
+        pc if pc == wboot_addr => ControlFlow::Break(()),
+        pc if pc == bdos_addr => match bdos.call(cpu) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(e) => {
+                result = Err(e);
+                ControlFlow::Break(())
+            }
+        },
+        _ => ControlFlow::Continue(()),
+    });
+    result?;
+    Ok(cpu)
+}
+
+/// Runs `rom` like `run_cpm` with the standard CP/M addresses and returns
+/// its console output, decoded as lossy UTF-8.
+pub fn run_cpm_to_string(rom: &[u8]) -> Result<String> {
+    let mut out = Vec::new();
+    run_cpm(rom, &mut out, BDOS_ADDR, WBOOT_ADDR)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Runs one of the standard 8080 diagnostics (TST8080, 8080PRE, CPUTEST or
+/// 8080EXM) with `run_cpm`. It passes if it reports success and no error
+/// before `DIAGNOSTIC_INSTRUCTION_LIMIT` instructions.
+pub fn run_diagnostic(rom: &[u8]) -> DiagnosticResult {
+    run_diagnostic_limited(rom, DIAGNOSTIC_INSTRUCTION_LIMIT)
+}
+
+/// `run_diagnostic` with its own instruction limit. A program still running
+/// after `limit` instructions fails with `hit_limit` set.
+pub fn run_diagnostic_limited(rom: &[u8], limit: u64) -> DiagnosticResult {
+    let mut out = Vec::new();
+    let instructions = match run_cpm_limited(rom, &mut out, BDOS_ADDR, WBOOT_ADDR, limit) {
+        Ok(cpu) => cpu.instruction_count(),
+        Err(e) => {
+            return DiagnosticResult {
+                output: format!("{:?}", e),
+                passed: false,
+                instructions: 0,
+                hit_limit: false,
+            }
+        }
+    };
+    let output = String::from_utf8_lossy(&out).into_owned();
+    let hit_limit = instructions >= limit;
+    let failed = output
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_ascii_alphanumeric()))
+        .any(|word| FAIL_MARKERS.contains(&word));
+    let passed = !hit_limit && !failed && PASS_MARKERS.iter().any(|m| output.contains(m));
+    DiagnosticResult {
+        output,
+        passed,
+        instructions,
+        hit_limit,
+    }
+}
+
+/// Console output for a program running under the BDOS.
+pub struct CpmBdos<'a> {
+    out: &'a mut dyn Write,
+}
+
+impl<'a> CpmBdos<'a> {
+    pub fn new(out: &'a mut dyn Write) -> Self {
+        Self { out }
+    }
+
+    /// Handles the BDOS function in C: 2 writes the character in E and 9
+    /// writes the `$`-terminated string at DE. Other functions are ignored.
+    pub fn call(&mut self, cpu: &CPU) -> io::Result<()> {
+        match cpu.registers[Register::C as usize] {
+            0x02 => self.out.write_all(&[cpu.registers[Register::E as usize]]),
+            0x09 => {
+                let d = cpu.registers[Register::D as usize];
+                let e = cpu.registers[Register::E as usize];
+                let mut addr = CPU::make_address(d, e) as u16;
+                loop {
+                    let ch = cpu.get_value(addr as usize);
+                    if ch == b'$' {
+                        return Ok(());
+                    }
+                    self.out.write_all(&[ch])?;
+                    addr = addr.wrapping_add(1);
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use std::fs;
+
+    const DIAGNOSTICS: [&str; 4] = ["TST8080.COM", "8080PRE.COM", "CPUTEST.COM", "8080EXM.COM"];
+
+    #[test]
+    fn test_bdos_output() {
+        let code = assemble(
+            "
+                    ORG 0x0100
+                    MVI C, 9
+                    MVI D, 0x01
+                    MVI E, 0x80
+                    CALL 5
+                    MVI C, 2
+                    MVI E, '!'
+                    CALL 5
+                    HLT
+                    ORG 0x0180
+                    DB \"hello$\"
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x0200];
+        data[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+        data[BDOS_ADDR as usize] = 0xC9;
+        let mut cpu = CPU::new(data);
+        cpu.set_pc(0x0100);
+
+        let mut out = Vec::new();
+        let mut bdos = CpmBdos::new(&mut out);
+        while !cpu.is_halted() {
+            cpu.run_once();
+            if cpu.pc() == BDOS_ADDR as usize {
+                bdos.call(&cpu).unwrap();
+            }
+        }
+        assert_eq!(out, b"hello!");
+    }
+
+    fn print_rom(message: &str) -> Vec<u8> {
+        let source = format!(
+            "
+                    ORG 0x0100
+                    MVI C, 9
+                    LXI D, msg
+                    CALL 5
+                    JMP 0
+            msg:    DB \"{}$\"
+            ",
+            message
+        );
+        assemble(&source).unwrap()
+    }
+
+    #[test]
+    fn test_run_diagnostic() {
+        let result = run_diagnostic(&print_rom(" CPU IS OPERATIONAL"));
+        assert_eq!(result.output, " CPU IS OPERATIONAL");
+        assert!(result.passed);
+        assert_eq!(result.instructions, 5);
+        assert!(!result.hit_limit);
+
+        assert!(!run_diagnostic(&print_rom(" CPU HAS FAILED!")).passed);
+        assert!(!run_diagnostic(&print_rom("  ERROR **** crc expected")).passed);
+        assert!(!run_diagnostic(&[0; 0x10000]).passed);
+
+        // failure words only count on their own, in capitals
+        let banner = "ERROR-FREE Failsafe edition -- CPU IS OPERATIONAL";
+        assert!(run_diagnostic(&print_rom(banner)).passed);
+    }
+
+    #[test]
+    fn test_run_diagnostic_limit() {
+        let rom = assemble("ORG 0x0100\nloop: JMP loop").unwrap();
+        let result = run_diagnostic_limited(&rom, 1000);
+        assert!(!result.passed);
+        assert!(result.hit_limit);
+        assert_eq!(result.instructions, 1000);
+    }
+
+    #[test]
+    fn test_run_cpm_to_string() {
+        let out = run_cpm_to_string(&print_rom(" CPU IS OPERATIONAL")).unwrap();
+        assert!(out.contains("CPU IS OPERATIONAL"));
+        assert!(run_cpm_to_string(&[0; 0x10000]).is_err());
+    }
+
+    #[test]
+    fn test_run_cpm_vectors() {
+        let rom = assemble(
+            "
+                    ORG 0x0100
+                    MVI C, 2
+                    MVI E, 'x'
+                    CALL 0x0050
+                    JMP 0x0040
+            ",
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        let cpu = run_cpm(&rom, &mut out, 0x0050, 0x0040).unwrap();
+        assert_eq!(out, b"x");
+        assert_eq!(cpu.pc(), 0x0040);
+    }
+
+    /// Runs the four diagnostics from `rom/`, see `rom/README.md`. Ignored by
+    /// default as 8080EXM takes minutes; run it with `cargo test --release --
+    /// --ignored`. A missing ROM fails the test.
+    #[test]
+    #[ignore]
+    fn test_bundled_diagnostics() {
+        for name in DIAGNOSTICS.iter() {
+            let path = format!("{}/rom/{}", env!("CARGO_MANIFEST_DIR"), name);
+            let rom = fs::read(&path).unwrap_or_else(|e| panic!("can't read {}: {}", path, e));
+            let result = run_diagnostic(&rom);
+            assert!(result.passed, "{} failed:\n{}", name, result.output);
+        }
+    }
+}
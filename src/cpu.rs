@@ -1,5 +1,19 @@
-use crate::opcode::Opcode;
-use crate::register::{Flag, Register};
+use crate::device::IoBus;
+use crate::disasm::disassemble;
+use crate::error::{Error, Result};
+use crate::memory::{Memory, MemoryBus, WriteMap};
+use crate::opcode::{OpClass, Opcode};
+use crate::register::{Flag, Register, RegisterPair};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+#[cfg(feature = "std")]
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::ops::{ControlFlow, Range, RangeInclusive};
+#[cfg(feature = "std")]
+use std::path::Path;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct CPU {
@@ -13,30 +27,450 @@ pub struct CPU {
     data: Vec<u8>,
     interrupted: bool,
     interrupted_addr: u16,
+    interrupt_vector_base: u16,
+    pending_interrupt: bool,
     halted: bool,
+    add_overflowed: bool,
+    sub_overflowed: bool,
+    strict_flags: bool,
+    update_flags: bool,
+    open_bus: u8,
+    input_queue: HashMap<u8, VecDeque<u8>>,
+    halt_on_write: Option<(usize, u8)>,
+    halted_by_write: bool,
+    write_map: Option<WriteMap>,
+    cycles: u64,
+    instructions: u64,
+    interrupts_serviced: u64,
+    dirty: Option<Dirty>,
+    profile: Option<Box<[u64; 256]>>,
+    mem_trace: Option<Vec<MemAccess>>,
+    smc: Option<Smc>,
+    initialized: Option<Vec<bool>>,
+    uninitialized_read: Option<u16>,
+    rewind: Option<Rewind>,
+    trace: Hook<BufWriter<Box<dyn Write>>>,
+    breakpoints: Hook<HashMap<u16, Option<Condition>>>,
+    device: Hook<Box<dyn Device>>,
+    illegal_opcode_handler: Hook<IllegalOpcodeHandler>,
+    opcode_trap: Hook<OpcodeTrap>,
+    call_hooks: Hook<HashMap<u16, CallHook>>,
+    step_hook: Hook<StepHook>,
+    wait_state_hook: Hook<WaitStateHook>,
+    events: Hook<VecDeque<(u64, Event)>>,
 }
 
+/// Decides whether a conditional breakpoint stops the CPU.
+pub type Condition = Box<dyn Fn(&CPU) -> bool>;
+
+/// Decides what an undocumented opcode byte does in `run_once_checked`.
+pub type IllegalOpcodeHandler = Box<dyn FnMut(&mut CPU, u8)>;
+
+/// A callback run by `CPU::schedule` once the cycle counter reaches its time.
+pub type Event = Box<dyn FnOnce(&mut CPU)>;
+
+/// Stands in for the subroutine at an address, see `CPU::set_call_hook`.
+pub type CallHook = Box<dyn FnMut(&mut CPU)>;
+
+/// Called with the CPU before each instruction `run_once` executes.
+pub type StepHook = Box<dyn FnMut(&CPU)>;
+
+/// Picks the opcodes `run_until_breakpoint` stops in front of.
+pub type OpcodeTrap = Box<dyn Fn(Opcode) -> bool>;
+
+/// Returns the wait states, in T-states, that a memory access costs.
+pub type WaitStateHook = Box<dyn FnMut(u16, AccessKind) -> u64>;
+
+/// The bits the rotate instructions move into and out of the carry.
+const HIGH_BIT: u8 = 0b1000_0000;
+const LOW_BIT: u8 = 0b0000_0001;
+
+/// The CRC-32 (IEEE) lookup table.
+static CRC32: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// What `CPU::execute` runs for one opcode byte.
+type Handler = fn(&mut CPU) -> u8;
+
+/// `execute` for the opcode byte `N`. Each copy keeps only its own arm of
+/// `dispatch`.
+fn execute_op<const N: u8>(cpu: &mut CPU) -> u8 {
+    cpu.execute_inline(Opcode::from(N), true)
+}
+
+/// The handlers for opcode bytes `$hi << 4` to `$hi << 4 | 0xF`.
+macro_rules! handler_row {
+    ($hi:literal) => {
+        [
+            execute_op::<{ $hi << 4 }>,
+            execute_op::<{ $hi << 4 | 0x1 }>,
+            execute_op::<{ $hi << 4 | 0x2 }>,
+            execute_op::<{ $hi << 4 | 0x3 }>,
+            execute_op::<{ $hi << 4 | 0x4 }>,
+            execute_op::<{ $hi << 4 | 0x5 }>,
+            execute_op::<{ $hi << 4 | 0x6 }>,
+            execute_op::<{ $hi << 4 | 0x7 }>,
+            execute_op::<{ $hi << 4 | 0x8 }>,
+            execute_op::<{ $hi << 4 | 0x9 }>,
+            execute_op::<{ $hi << 4 | 0xA }>,
+            execute_op::<{ $hi << 4 | 0xB }>,
+            execute_op::<{ $hi << 4 | 0xC }>,
+            execute_op::<{ $hi << 4 | 0xD }>,
+            execute_op::<{ $hi << 4 | 0xE }>,
+            execute_op::<{ $hi << 4 | 0xF }>,
+        ]
+    };
+}
+
+/// One handler per opcode byte, indexed by `run_once` and `execute`. Each is
+/// `execute` compiled for that opcode alone, which runs faster than matching
+/// on the opcode at run time.
+static DISPATCH: [Handler; 256] = flatten_handlers([
+    handler_row!(0x0),
+    handler_row!(0x1),
+    handler_row!(0x2),
+    handler_row!(0x3),
+    handler_row!(0x4),
+    handler_row!(0x5),
+    handler_row!(0x6),
+    handler_row!(0x7),
+    handler_row!(0x8),
+    handler_row!(0x9),
+    handler_row!(0xA),
+    handler_row!(0xB),
+    handler_row!(0xC),
+    handler_row!(0xD),
+    handler_row!(0xE),
+    handler_row!(0xF),
+]);
+
+const fn flatten_handlers(rows: [[Handler; 16]; 16]) -> [Handler; 256] {
+    let mut table = [rows[0][0]; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = rows[i >> 4][i & 0xF];
+        i += 1;
+    }
+    table
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+/// Feeds `bytes` into a running CRC-32 that has not been finalised yet.
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &b| {
+        (crc >> 8) ^ CRC32[((crc ^ u32::from(b)) & 0xFF) as usize]
+    })
+}
+
+/// A snapshot of the registers and control state, without memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    /// B C D E H L
+    pub registers: [u8; 6],
+    pub acc: u8,
+    pub flag: Flag,
+    pub sp: u16,
+    pub pc: u16,
+    pub halted: bool,
+    pub interrupts_enabled: bool,
+}
+
+/// The outcome of `CPU::tick`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    Running,
+    /// Halted with no interrupt able to wake the CPU right now.
+    Halted,
+    /// The undocumented opcode at PC was not executed.
+    IllegalOpcode(u8),
+    /// The instruction at PC runs past the end of memory and was not executed.
+    OutOfBounds,
+    /// The instruction just executed read this never-written address.
+    UninitializedRead(u16),
+}
+
+/// What a single `step_debug` executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepRecord {
+    pub pc: u16,
+    pub opcode: Opcode,
+    /// T-states taken, including the extra cost of a taken conditional CALL or
+    /// RET and anything else counted during the step, such as wait states.
+    pub cycles: u64,
+    /// For conditional jumps, calls and returns, whether the branch was taken.
+    pub branch_taken: Option<bool>,
+    /// `CPU::cycles` after the step.
+    pub total_cycles: u64,
+    /// `CPU::instruction_count` after the step.
+    pub total_instructions: u64,
+}
+
+/// A `StepRecord` with what the step changed, from `step_n_with_deltas`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepDelta {
+    pub step: StepRecord,
+    /// Each of B-L and A that changed, with its value before and after.
+    pub registers: Vec<(Register, u8, u8)>,
+    /// The flags before and after, if they changed.
+    pub flags: Option<(Flag, Flag)>,
+    /// PC before and after.
+    pub pc: (u16, u16),
+}
+
+/// Where the instruction at PC can go next, from `CPU::successors`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Successors {
+    /// The address just past the instruction.
+    pub fall_through: u16,
+    /// Where a jump, call, return, `RST` or `PCHL` goes when it is taken, or
+    /// `None` for other instructions. Returns and `PCHL` use the current top
+    /// of the stack and HL.
+    pub branch_target: Option<u16>,
+}
+
+/// Why the CPU touched memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// An opcode or operand byte of the instruction being executed.
+    Fetch,
+}
+
+/// One entry of the log kept by `enable_mem_trace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+/// The code range watched by `enable_smc_detection` and the writes into it.
+#[derive(Debug, Clone)]
+struct Smc {
+    code: RangeInclusive<usize>,
+    events: Vec<u16>,
+}
+
+/// The memory window watched by `enable_dirty_tracking`.
+#[derive(Debug, Clone)]
+struct Dirty {
+    start: usize,
+    cells: Vec<bool>,
+}
+
+impl Dirty {
+    #[inline]
+    fn mark(&mut self, addr: usize) {
+        if let Some(cell) = addr
+            .checked_sub(self.start)
+            .and_then(|i| self.cells.get_mut(i))
+        {
+            *cell = true;
+        }
+    }
+}
+
+/// What one step changed, so `rewind` can undo it.
+#[derive(Debug, Clone)]
+struct Delta {
+    state: CpuState,
+    pending_interrupt: bool,
+    interrupted_addr: u16,
+    cycles: u64,
+    instructions: u64,
+    interrupts_serviced: u64,
+    /// The old value of each written cell, in the order they were written.
+    writes: Vec<(usize, u8)>,
+}
+
+/// The last `depth` steps, oldest first.
+#[derive(Debug, Clone)]
+struct Rewind {
+    depth: usize,
+    deltas: VecDeque<Delta>,
+}
+
+/// Host-side state attached to the CPU, such as a trace writer or a callback.
+/// It is not part of the machine, so a cloned CPU starts without it.
+///
+/// A callback that receives the CPU can't be called while it is borrowed from
+/// its own field, so it is taken out of the `Option` for the call and put
+/// back afterwards, unless it installed a replacement in the meantime.
+struct Hook<T>(Option<T>);
+
+impl<T> Default for Hook<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<T> Clone for Hook<T> {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+impl<T> fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Some(..)"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+/// Hardware on the other side of `IN` and `OUT`.
 pub trait Device {
     fn input(&mut self, port: u8) -> u8;
     fn output(&mut self, port: u8, data: u8);
+
+    /// Whether the device answers `IN` on `port`. The CPU reads the open-bus
+    /// value from ports it doesn't.
+    fn is_mapped(&self, _port: u8) -> bool {
+        true
+    }
+}
+
+/// Lets one device answer on several ports of an `IoBus`.
+impl<D: Device + ?Sized> Device for Rc<RefCell<D>> {
+    fn input(&mut self, port: u8) -> u8 {
+        self.borrow_mut().input(port)
+    }
+
+    fn output(&mut self, port: u8, data: u8) {
+        self.borrow_mut().output(port, data)
+    }
+
+    fn is_mapped(&self, port: u8) -> bool {
+        self.borrow().is_mapped(port)
+    }
+}
+
+/// A CPU with 64 KiB of zeroed memory.
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new(vec![0; 0x10000])
+    }
+}
+
+/// Gives devices such as `DmaController` the CPU's memory, with writes going
+/// through `set_value` like an instruction's.
+impl MemoryBus for CPU {
+    fn read(&self, addr: u16) -> u8 {
+        self.get_value(usize::from(addr))
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.set_value(usize::from(addr), val)
+    }
 }
 
 impl CPU {
+    /// Creates a CPU at PC 0 with `data` as memory.
+    ///
+    /// Instructions can only address the low 64 KiB. Memory smaller than that
+    /// is mirrored: a data access to `addr` lands on `addr % data.len()`, like
+    /// a board that leaves the high address lines undecoded. Opcode fetches
+    /// are not mirrored, but operand bytes are read like data.
+    ///
+    /// PC wraps from 0xFFFF to 0 like on real hardware, so an instruction at
+    /// the top of memory takes its operands from address 0 onwards.
+    ///
+    /// SP starts just past the end of memory, so the first push writes the
+    /// last bytes. For a full 64 KiB (or larger) image that is 0, which the
+    /// first push wraps to 0xFFFF.
     #[inline]
     pub fn new(data: Vec<u8>) -> Self {
+        let sp = if data.len() > 0xFFFF {
+            0
+        } else {
+            data.len() as u16
+        };
         Self {
             flag: Flag::default(),
             registers: [0, 0, 0, 0, 0, 0],
             acc: 0,
-            sp: data.len() as u16,
+            sp,
             pc: 0,
             data,
             interrupted: true,
             interrupted_addr: 0,
+            interrupt_vector_base: 0,
+            pending_interrupt: false,
             halted: false,
+            add_overflowed: false,
+            sub_overflowed: false,
+            strict_flags: true,
+            update_flags: true,
+            open_bus: 0xFF,
+            input_queue: HashMap::new(),
+            halt_on_write: None,
+            halted_by_write: false,
+            write_map: None,
+            cycles: 0,
+            instructions: 0,
+            interrupts_serviced: 0,
+            dirty: None,
+            profile: None,
+            mem_trace: None,
+            smc: None,
+            initialized: None,
+            uninitialized_read: None,
+            rewind: None,
+            trace: Hook::default(),
+            breakpoints: Hook::default(),
+            device: Hook::default(),
+            illegal_opcode_handler: Hook::default(),
+            opcode_trap: Hook::default(),
+            call_hooks: Hook::default(),
+            step_hook: Hook::default(),
+            wait_state_hook: Hook::default(),
+            events: Hook::default(),
         }
     }
 
+    /// Runs code out of `memory`. Fetches and reads see what `memory` reads
+    /// at the start; writes to ROM and unmapped addresses are ignored, and a
+    /// write to mirrored RAM shows up at every address mirroring that cell.
+    ///
+    /// Like `new` with a full 64 KiB image, so SP starts at 0.
+    pub fn with_memory(memory: Memory) -> Self {
+        let data = (0..=0xFFFF).map(|addr| memory.read(addr)).collect();
+        let mut cpu = Self::new(data);
+        cpu.write_map = Some(memory.write_map());
+        cpu
+    }
+
+    /// Like `new`, with `bus` handling `IN` and `OUT`.
+    pub fn with_devices(data: Vec<u8>, bus: IoBus) -> Self {
+        let mut cpu = Self::new(data);
+        cpu.set_device(Box::new(bus));
+        cpu
+    }
+
     #[inline]
     pub fn pc(&self) -> usize {
         self.pc as usize
@@ -48,19 +482,8 @@ impl CPU {
     }
 
     #[inline]
-    fn imm_u8(&mut self) -> u8 {
-        let res = self.data[self.pc() + 1];
-        self.pc += 1;
-        res
-    }
-
-    #[inline]
-    fn imm_u16(&mut self) -> u16 {
-        let res = self.data[self.pc() + 1];
-        let res2 = self.data[self.pc() + 2];
-        let res = Self::compose_to_u16(res, res2);
-        self.pc += 2;
-        res
+    pub fn memory_size(&self) -> usize {
+        self.data.len()
     }
 
     #[inline]
@@ -68,1736 +491,5382 @@ impl CPU {
         self.sp as usize
     }
 
-    #[inline]
-    pub fn set_value(&mut self, addr: usize, val: u8) {
-        self.data[addr] = val;
+    /// Copies `bytes` into memory starting at `origin`.
+    pub fn load_at(&mut self, origin: usize, bytes: &[u8]) -> Result<()> {
+        match origin.checked_add(bytes.len()) {
+            Some(end) if end <= self.data.len() => {
+                self.data[origin..end].copy_from_slice(bytes);
+                if let Some(initialized) = self.initialized.as_mut() {
+                    initialized[origin..end]
+                        .iter_mut()
+                        .for_each(|cell| *cell = true);
+                }
+                Ok(())
+            }
+            _ => Err(Error::OutOfBounds),
+        }
     }
 
-    #[inline]
-    pub fn get_value(&self, addr: usize) -> u8 {
-        self.data[addr]
+    /// Like `load_at`, but first fills the rest of memory with `fill`, e.g.
+    /// `HLT` (0x76) so a runaway PC stops instead of running stale bytes.
+    /// Memory is left alone if `bytes` does not fit.
+    pub fn load_program(&mut self, offset: usize, bytes: &[u8], fill: u8) -> Result<()> {
+        match offset.checked_add(bytes.len()) {
+            Some(end) if end <= self.data.len() => {
+                self.data.fill(fill);
+                self.load_at(offset, bytes)
+            }
+            _ => Err(Error::OutOfBounds),
+        }
     }
 
-    #[inline]
-    pub fn make_address(val1: u8, val2: u8) -> usize {
-        Self::compose_to_u16(val1, val2) as usize
+    /// Creates a CPU with `mem_size` bytes of zeroed memory, loads the file at
+    /// `path` into it at `load_offset` and starts it there.
+    ///
+    /// Fails with `InvalidData` if the file does not fit.
+    #[cfg(feature = "std")]
+    pub fn from_rom_file(
+        path: impl AsRef<Path>,
+        mem_size: usize,
+        load_offset: usize,
+    ) -> io::Result<CPU> {
+        let rom = fs::read(path)?;
+        let mut cpu = CPU::new(vec![0; mem_size]);
+        cpu.load_at(load_offset, &rom).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes do not fit at {:#06X}", rom.len(), load_offset),
+            )
+        })?;
+        cpu.set_pc(load_offset as u16);
+        Ok(cpu)
     }
 
-    #[inline]
-    fn compose_to_u16(val1: u8, val2: u8) -> u16 {
-        u16::from(val1) << 8 | u16::from(val2)
+    /// Writes the whole memory image to `path` as raw bytes.
+    #[cfg(feature = "std")]
+    pub fn save_memory(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, &self.data)
+    }
+
+    /// Loads a raw image written by `save_memory` (or any dump no larger than
+    /// memory) from `path` into memory starting at 0. Bytes past the end of
+    /// the file are left alone.
+    #[cfg(feature = "std")]
+    pub fn load_memory(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let bytes = fs::read(path)?;
+        self.load_at(0, &bytes).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} bytes do not fit in {} bytes of memory",
+                    bytes.len(),
+                    self.data.len()
+                ),
+            )
+        })
+    }
+
+    /// Loads each `(origin, bytes)` pair. Nothing is written unless every
+    /// segment fits in memory and no two segments overlap.
+    pub fn load_segments(&mut self, segments: &[(usize, &[u8])]) -> Result<()> {
+        let mut ranges = Vec::with_capacity(segments.len());
+        for &(origin, bytes) in segments {
+            match origin.checked_add(bytes.len()) {
+                Some(end) if end <= self.data.len() => ranges.push(origin..end),
+                _ => return Err(Error::OutOfBounds),
+            }
+        }
+        ranges.sort_by_key(|range| range.start);
+        if ranges.windows(2).any(|pair| pair[0].end > pair[1].start) {
+            return Err(Error::OverlappingSegments);
+        }
+        for &(origin, bytes) in segments {
+            self.load_at(origin, bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            acc: self.acc,
+            flag: self.flag,
+            sp: self.sp,
+            pc: self.pc,
+            halted: self.halted,
+            interrupts_enabled: self.interrupted,
+        }
     }
 
+    /// Puts the registers and control state back as `state` recorded them.
+    /// Memory is left alone.
+    pub fn restore_state(&mut self, state: &CpuState) {
+        self.registers = state.registers;
+        self.acc = state.acc;
+        self.flag = state.flag;
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.halted = state.halted;
+        self.interrupted = state.interrupts_enabled;
+    }
+
+    /// B, C, D, E, H and L.
     #[inline]
-    fn decompose_to_u8(val: u16) -> (u8, u8) {
-        let res = ((val & 0xFF_00) >> 8) as u8;
-        let res2 = (val & 0x00_FF) as u8;
-        (res, res2)
+    pub fn registers(&self) -> [u8; 6] {
+        self.registers
     }
 
     #[inline]
-    pub fn set_memory_address(&mut self, addr: u16) {
-        let (h, l) = Self::decompose_to_u8(addr);
-        self.registers[Register::H as usize] = h;
-        self.registers[Register::L as usize] = l;
+    pub fn set_registers(&mut self, registers: [u8; 6]) {
+        self.registers = registers;
     }
 
     #[inline]
-    fn memory_address(&self) -> usize {
-        Self::make_address(
-            self.registers[Register::H as usize],
-            self.registers[Register::L as usize],
-        )
+    pub fn set_acc(&mut self, acc: u8) {
+        self.acc = acc;
+    }
+
+    /// Sets every register in the order debuggers usually print them, e.g. from
+    /// a captured dump. Like `load_regs`, the control state is left alone.
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_state_raw(&mut self, a: u8, flags: u8, bc: u16, de: u16, hl: u16, sp: u16, pc: u16) {
+        self.acc = a;
+        self.flag = Flag::new(flags);
+        self.set_pair(RegisterPair::BC, bc);
+        self.set_pair(RegisterPair::DE, de);
+        self.set_pair(RegisterPair::HL, hl);
+        self.sp = sp;
+        self.pc = pc;
+    }
+
+    /// Loads B-L, A and the flags from `state`. Unlike `restore_state`, SP, PC
+    /// and the control state are left alone.
+    pub fn load_regs(&mut self, state: &CpuState) {
+        self.registers = state.registers;
+        self.acc = state.acc;
+        self.flag = state.flag;
     }
 
     #[inline]
-    fn register_or_memory_data(&self, reg: u8) -> u8 {
-        if reg == Register::Mem as u8 {
-            self.data[self.memory_address()]
-        } else if reg == Register::Acc as u8 {
-            self.acc
-        } else {
-            self.registers[reg as usize]
-        }
+    pub fn flags(&self) -> Flag {
+        self.flag
     }
 
     #[inline]
-    fn set_register_or_memory_data(&mut self, reg: u8, data: u8) {
-        if reg == Register::Mem as u8 {
-            let addr = self.memory_address();
-            self.data[addr] = data;
-        } else if reg == Register::Acc as u8 {
-            self.acc = data;
-        } else {
-            self.registers[reg as usize] = data;
-        }
+    pub fn set_flags(&mut self, flag: Flag) {
+        self.flag = flag;
     }
 
+    /// The accumulator and flags as one word, laid out as `PUSH PSW` stores
+    /// them: A in the high byte.
     #[inline]
-    fn set_jump_pc(&mut self) {
-        self.pc = Self::compose_to_u16(self.data[self.pc() - 1], self.data[self.pc() - 2]);
+    pub fn psw(&self) -> u16 {
+        Self::compose_to_u16(self.acc, self.flag.value())
     }
 
-    fn inc(val1: u8, val2: u8) -> (u8, u8) {
-        let data = Self::compose_to_u16(val1, val2);
-        let res = data.wrapping_add(1);
-        Self::decompose_to_u8(res)
+    /// The registers, flags and a CRC-32 of memory as a one-line JSON object,
+    /// e.g. for comparing runs against another emulator from a script:
+    /// `{"pc":256,"sp":0,"a":0,"b":0,"c":0,"d":0,"e":0,"h":0,"l":0,"flags":2,"mem_crc":0}`.
+    pub fn dump_state_json(&self) -> String {
+        let [b, c, d, e, h, l] = self.registers;
+        format!(
+            "{{\"pc\":{},\"sp\":{},\"a\":{},\"b\":{},\"c\":{},\"d\":{},\"e\":{},\"h\":{},\"l\":{},\"flags\":{},\"mem_crc\":{}}}",
+            self.pc,
+            self.sp,
+            self.acc,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            self.flag.value(),
+            self.memory_crc32()
+        )
     }
 
-    fn dec(val1: u8, val2: u8) -> (u8, u8) {
-        let data = Self::compose_to_u16(val1, val2);
-        let res = data.wrapping_sub(1);
-        Self::decompose_to_u8(res)
+    /// The CRC-32 (IEEE, as used by zip and 8080EXM) of all of memory.
+    pub fn memory_crc32(&self) -> u32 {
+        crc32(&self.data)
     }
 
-    #[inline]
-    fn update_zero_flag(&mut self, val: u8) {
-        self.flag.set_zero_flag(val == 0);
+    /// Like `memory_crc32`, but also covers B-L, A, the flags, SP and PC, which
+    /// are fed in before memory in that order, words little-endian.
+    pub fn state_crc32(&self) -> u32 {
+        let [sp_low, sp_high] = self.sp.to_le_bytes();
+        let [pc_low, pc_high] = self.pc.to_le_bytes();
+        let mut crc = crc32_update(!0, &self.registers);
+        crc = crc32_update(
+            crc,
+            &[
+                self.acc,
+                self.flag.value(),
+                sp_low,
+                sp_high,
+                pc_low,
+                pc_high,
+            ],
+        );
+        !crc32_update(crc, &self.data)
+    }
+
+    /// Whether both CPUs have the same registers, flags, SP, PC and memory.
+    ///
+    /// Host-side attachments such as traces and breakpoints are not compared.
+    pub fn architectural_eq(&self, other: &CPU) -> bool {
+        self.registers == other.registers
+            && self.acc == other.acc
+            && self.flag == other.flag
+            && self.sp == other.sp
+            && self.pc == other.pc
+            && self.data == other.data
     }
 
+    /// Writes memory. Every write made by an instruction goes through here.
     #[inline]
-    fn update_sign_flag(&mut self, val: u8) {
-        self.flag.set_sign_flag(val & 0b1000_0000 != 0);
+    pub fn set_value(&mut self, addr: usize, val: u8) {
+        let addr = self.wrap(addr);
+        self.log_access(addr, val, AccessKind::Write);
+        if self.write_map.is_none() {
+            self.store(addr, val);
+            return;
+        }
+        let map = self.write_map.take().unwrap();
+        match map.cell(addr as u16).map(|cell| map.aliases(cell)) {
+            Some(Some(addrs)) => {
+                for &alias in addrs {
+                    self.store(usize::from(alias), val);
+                }
+            }
+            Some(None) => self.store(addr, val),
+            None => {}
+        }
+        self.write_map = Some(map);
     }
 
+    /// Stores `val` at the already wrapped `addr` and does the bookkeeping.
     #[inline]
-    fn update_carry_flag_with_carry(&mut self, val1: u8, val2: u8, carry: u8) {
-        if u16::from(val1) + u16::from(val2) + u16::from(carry) > 0xff {
-            self.flag.set_carry_flag(true);
-        } else {
-            self.flag.set_carry_flag(false);
+    fn store(&mut self, addr: usize, val: u8) {
+        if let Some(delta) = self.rewind.as_mut().and_then(|r| r.deltas.back_mut()) {
+            delta.writes.push((addr, self.data[addr]));
+        }
+        self.data[addr] = val;
+        if let Some(initialized) = self.initialized.as_mut() {
+            initialized[addr] = true;
+        }
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.mark(addr);
+        }
+        if let Some(smc) = self.smc.as_mut() {
+            if smc.code.contains(&addr) {
+                smc.events.push(addr as u16);
+            }
+        }
+        if self.halt_on_write == Some((addr, val)) {
+            self.halted = true;
+            self.halted_by_write = true;
         }
     }
 
+    /// Halts the CPU when `value` is written to `addr`, the way some test ROMs
+    /// signal that they are done. `halted_by_write` then reports it.
+    pub fn set_halt_on_write(&mut self, addr: u16, value: u8) {
+        self.halt_on_write = Some((self.wrap(usize::from(addr)), value));
+    }
+
+    /// Whether the write set up by `set_halt_on_write` has happened since the
+    /// last `reset`.
     #[inline]
-    fn update_aux_flag(&mut self, val1: u8, val2: u8) {
-        self.flag
-            .set_auxiliary_carry_flag((val1 & 0b0000_1111) + (val2 & 0b0000_1111) > 0b0000_1111);
+    pub fn halted_by_write(&self) -> bool {
+        self.halted_by_write
     }
 
+    /// Reads memory on behalf of an instruction, logging it for `enable_mem_trace`.
     #[inline]
-    fn update_aux_flag_with_carry(&mut self, val1: u8, val2: u8, carry: u8) {
-        self.flag.set_auxiliary_carry_flag(
-            (val1 & 0b0000_1111) + (val2 & 0b0000_1111) + carry > 0b0000_1111,
-        );
+    fn load(&mut self, addr: usize) -> u8 {
+        let addr = self.wrap(addr);
+        let val = self.data[addr];
+        self.log_access(addr, val, AccessKind::Read);
+        if self
+            .initialized
+            .as_ref()
+            .is_some_and(|initialized| !initialized[addr])
+        {
+            self.uninitialized_read.get_or_insert(addr as u16);
+        }
+        val
+    }
+
+    /// Starts tracking which cells have been given a value, by `load_at` and
+    /// the functions built on it or by a write, so `run_once_checked` can
+    /// report an instruction that reads any other cell. Everything counts as
+    /// uninitialized at first, so call this before loading the program.
+    pub fn enable_uninitialized_read_detection(&mut self) {
+        self.initialized = Some(vec![false; self.data.len()]);
     }
 
     #[inline]
-    fn update_parity_flag(&mut self, val: u8) {
-        self.flag
-            .set_parity_flag(val.count_ones() & 0b0000_0001 == 0);
+    fn log_access(&mut self, addr: usize, value: u8, kind: AccessKind) {
+        if let Some(hook) = self.wait_state_hook.0.as_mut() {
+            self.cycles += hook(addr as u16, kind);
+        }
+        if let Some(log) = self.mem_trace.as_mut() {
+            log.push(MemAccess {
+                addr: addr as u16,
+                value,
+                kind,
+            });
+        }
     }
 
-    fn stack_push_u8(&mut self, val: u8) {
-        self.sp = self.sp.wrapping_sub(1);
-        let sp = self.sp();
-        self.data[sp] = val;
+    /// Starts logging every memory access made by instructions: the bytes of
+    /// each instruction as it is fetched, then its reads and writes, in order.
+    /// Writes made with `set_value` are logged too.
+    pub fn enable_mem_trace(&mut self) {
+        self.mem_trace.get_or_insert_with(Vec::new);
     }
 
-    fn stack_pop_u8(&mut self) -> u8 {
-        let res = self.data[self.sp()];
-        self.sp = self.sp.wrapping_add(1);
-        res
+    /// Returns the accesses logged since the last call and clears the log.
+    pub fn take_mem_trace(&mut self) -> Vec<MemAccess> {
+        self.mem_trace
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
     }
 
-    pub fn stack_push(&mut self, val: u16) {
-        let (val1, val2) = Self::decompose_to_u8(val);
-        self.stack_push_u8(val1);
-        self.stack_push_u8(val2);
+    /// Starts recording writes into `code_start..=code_end`, the range the
+    /// program executes from, to spot self-modifying code.
+    pub fn enable_smc_detection(&mut self, code_start: u16, code_end: u16) {
+        self.smc = Some(Smc {
+            code: usize::from(code_start)..=usize::from(code_end),
+            events: Vec::new(),
+        });
     }
 
-    pub fn stack_pop(&mut self) -> u16 {
-        let val1 = self.stack_pop_u8();
-        let val2 = self.stack_pop_u8();
-        Self::compose_to_u16(val2, val1)
+    /// Returns the addresses written in the code range since the last call,
+    /// in order, and clears them.
+    pub fn take_smc_events(&mut self) -> Vec<u16> {
+        self.smc
+            .as_mut()
+            .map(|smc| std::mem::take(&mut smc.events))
+            .unwrap_or_default()
     }
 
+    /// Reads the little-endian word at `addr`, the byte order of every 16-bit
+    /// operand and of `LHLD`/`SHLD`.
     #[inline]
-    fn op_return(&mut self) {
-        self.pc = self.stack_pop();
+    pub fn read_u16(&self, addr: usize) -> u16 {
+        Self::compose_to_u16(self.get_value(addr + 1), self.get_value(addr))
     }
 
+    /// Writes `val` at `addr`, low byte first.
     #[inline]
-    fn op_call(&mut self) {
-        let val1 = self.data[self.pc() - 2];
-        let val2 = self.data[self.pc() - 1];
-        self.stack_push(self.pc);
-        self.pc = Self::compose_to_u16(val2, val1);
+    pub fn write_u16(&mut self, addr: usize, val: u16) {
+        let (high, low) = Self::decompose_to_u8(val);
+        self.set_value(addr, low);
+        self.set_value(addr + 1, high);
     }
 
-    fn device_input(&mut self, device: &mut Device, port: u8) -> u8 {
-        //todo
-        device.input(port)
+    /// Like `read_u16`, but fails instead of reading past the end of memory.
+    pub fn try_read_u16(&self, addr: usize) -> Result<u16> {
+        if addr < self.data.len().saturating_sub(1) {
+            Ok(self.read_u16(addr))
+        } else {
+            Err(Error::OutOfBounds)
+        }
     }
 
-    fn device_output(&mut self, device: &mut Device, port: u8, data: u8) {
-        //todo
-        device.output(port, data);
+    /// Like `write_u16`, but fails instead of writing past the end of memory.
+    pub fn try_write_u16(&mut self, addr: usize, val: u16) -> Result<()> {
+        if addr < self.data.len().saturating_sub(1) {
+            self.write_u16(addr, val);
+            Ok(())
+        } else {
+            Err(Error::OutOfBounds)
+        }
     }
 
-    #[inline]
-    pub fn is_halted(&self) -> bool {
-        self.halted
+    /// Reads bit `bit` (0 is the least significant) of the byte at `addr`.
+    /// Fails for a bit above 7 or an address past the end of memory.
+    pub fn get_mem_bit(&self, addr: usize, bit: u8) -> Result<bool> {
+        if bit > 7 {
+            return Err(Error::IllegalValue);
+        }
+        let val = *self.data.get(addr).ok_or(Error::OutOfBounds)?;
+        Ok(val & 1 << bit != 0)
     }
 
-    pub fn execute(&mut self, opcode: Opcode) {
-        match opcode {
-            Opcode::NOP => {
-                self.pc += 1;
-            }
+    /// Sets or clears bit `bit` of the byte at `addr`, leaving the others.
+    /// Fails like `get_mem_bit`.
+    pub fn set_mem_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.get_mem_bit(addr, bit)?;
+        let val = self.data[addr];
+        let mask = 1 << bit;
+        self.set_value(addr, if value { val | mask } else { val & !mask });
+        Ok(())
+    }
 
-            Opcode::LXI_B | Opcode::LXI_D | Opcode::LXI_H | Opcode::LXI_SP => {
-                if opcode.get_rp_num_2() != 0b11 {
-                    let reg1 = opcode.get_rp_num_2() << 1;
-                    let reg2 = (opcode.get_rp_num_2() << 1) + 1;
-                    self.registers[reg1 as usize] = self.data[self.pc() + 1];
-                    self.registers[reg2 as usize] = self.data[self.pc() + 2];
-                } else {
-                    self.sp = (u16::from(self.data[self.pc() + 1]) << 8)
-                        + u16::from(self.data[self.pc() + 2]);
+    /// Starts recording what each step changes, keeping the last `depth`
+    /// steps for `rewind`. Only the registers and the written cells are kept,
+    /// not a copy of memory.
+    pub fn enable_rewind(&mut self, depth: usize) {
+        self.rewind = Some(Rewind {
+            depth,
+            deltas: VecDeque::with_capacity(depth),
+        });
+    }
+
+    /// Undoes the last `steps` steps, or as many as were recorded, restoring
+    /// registers, memory and counters. Fails if `enable_rewind` was not called.
+    pub fn rewind(&mut self, steps: usize) -> Result<()> {
+        let mut rewind = self.rewind.take().ok_or(Error::RewindDisabled)?;
+        for _ in 0..steps {
+            let delta = match rewind.deltas.pop_back() {
+                Some(delta) => delta,
+                None => break,
+            };
+            for &(addr, val) in delta.writes.iter().rev() {
+                self.data[addr] = val;
+                if let Some(dirty) = self.dirty.as_mut() {
+                    dirty.mark(addr);
                 }
-                self.pc += 3;
             }
+            self.restore_state(&delta.state);
+            self.pending_interrupt = delta.pending_interrupt;
+            self.interrupted_addr = delta.interrupted_addr;
+            self.cycles = delta.cycles;
+            self.instructions = delta.instructions;
+            self.interrupts_serviced = delta.interrupts_serviced;
+        }
+        self.rewind = Some(rewind);
+        Ok(())
+    }
 
-            Opcode::STAX_B | Opcode::STAX_D => {
-                self.pc += 1;
-                let reg1 = (opcode.get_rp_num() as usize) << 1;
-                let reg2 = (opcode.get_rp_num() << 1) as usize + 1;
-                let val1 = self.registers[reg1];
-                let val2 = self.registers[reg2];
-                self.data[Self::make_address(val1, val2)] = self.acc;
+    #[inline]
+    fn record_step(&mut self) {
+        if self.rewind.is_none() {
+            return;
+        }
+        let delta = Delta {
+            state: self.state(),
+            pending_interrupt: self.pending_interrupt,
+            interrupted_addr: self.interrupted_addr,
+            cycles: self.cycles,
+            instructions: self.instructions,
+            interrupts_serviced: self.interrupts_serviced,
+            writes: Vec::new(),
+        };
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.deltas.push_back(delta);
+            if rewind.deltas.len() > rewind.depth {
+                rewind.deltas.pop_front();
             }
+        }
+    }
 
-            Opcode::STA => {
-                let low_addr = self.data[self.pc() + 1];
-                let high_addr = self.data[self.pc() + 2];
-                let addr = Self::make_address(high_addr, low_addr);
-                self.pc += 3;
-                self.data[addr] = self.acc;
-            }
+    /// Starts recording writes to `start..end`, e.g. a framebuffer, replacing
+    /// any window watched before.
+    pub fn enable_dirty_tracking(&mut self, start: usize, end: usize) {
+        self.dirty = Some(Dirty {
+            start,
+            cells: vec![false; end.saturating_sub(start)],
+        });
+    }
 
-            Opcode::LDAX_B | Opcode::LDAX_D => {
-                self.pc += 1;
-                let reg1 = (opcode.get_rp_num() as usize) << 1;
-                let reg2 = (opcode.get_rp_num() << 1) as usize + 1;
-                let val1 = self.registers[reg1];
-                let val2 = self.registers[reg2];
-                self.acc = self.data[Self::make_address(val1, val2)];
+    /// Returns the written parts of the watched window as ascending,
+    /// coalesced ranges and forgets them.
+    pub fn take_dirty_ranges(&mut self) -> Vec<Range<usize>> {
+        let dirty = match self.dirty.as_mut() {
+            Some(dirty) => dirty,
+            None => return Vec::new(),
+        };
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (i, cell) in dirty.cells.iter_mut().enumerate() {
+            if !std::mem::replace(cell, false) {
+                continue;
             }
-
-            Opcode::LDA => {
-                let low_addr = self.data[self.pc() + 1];
-                let high_addr = self.data[self.pc() + 2];
-                let addr = Self::make_address(high_addr, low_addr);
-                self.pc += 3;
-                self.acc = self.data[addr];
+            let addr = dirty.start + i;
+            match ranges.last_mut() {
+                Some(last) if last.end == addr => last.end += 1,
+                _ => ranges.push(addr..addr + 1),
             }
+        }
+        ranges
+    }
 
-            Opcode::INX_B | Opcode::INX_D | Opcode::INX_H | Opcode::INX_SP => {
-                if opcode.get_rp_num_2() != 0b11 {
-                    let reg1 = (opcode.get_rp_num_2() << 1) as usize;
-                    let reg2 = (opcode.get_rp_num_2() << 1) as usize + 1;
-                    let (val1, val2) = Self::inc(self.registers[reg1], self.registers[reg2]);
-                    self.registers[reg1] = val1;
-                    self.registers[reg2] = val2;
-                } else {
-                    self.sp = self.sp.wrapping_add(1);
-                }
-                self.pc += 1;
-            }
+    #[inline]
+    pub fn get_value(&self, addr: usize) -> u8 {
+        self.data[self.wrap(addr)]
+    }
 
-            Opcode::INR_A
-            | Opcode::INR_B
-            | Opcode::INR_C
-            | Opcode::INR_D
-            | Opcode::INR_E
-            | Opcode::INR_H
-            | Opcode::INR_L
-            | Opcode::INR_M => {
-                let reg = opcode.get_dest_num();
-                let data = self.register_or_memory_data(reg);
-                self.flag.set_auxiliary_carry_flag(data & 0b0000_1111 == 15);
-                let data = data.wrapping_add(1);
-                self.update_sign_flag(data);
-                self.update_zero_flag(data);
-                self.update_parity_flag(data);
-                self.set_register_or_memory_data(reg, data);
-                self.pc += 1;
-            }
+    /// Where a data access to `addr` lands, see `new`.
+    #[inline]
+    fn wrap(&self, addr: usize) -> usize {
+        if addr < self.data.len() {
+            addr
+        } else {
+            addr % self.data.len()
+        }
+    }
 
-            Opcode::DCR_A
-            | Opcode::DCR_B
-            | Opcode::DCR_C
-            | Opcode::DCR_D
-            | Opcode::DCR_E
-            | Opcode::DCR_H
-            | Opcode::DCR_L
-            | Opcode::DCR_M => {
-                let reg = opcode.get_dest_num();
-                let data = self.register_or_memory_data(reg);
-                self.flag.set_auxiliary_carry_flag(data & 0b0000_1111 == 15);
-                let data = data.wrapping_sub(1);
-                self.update_sign_flag(data);
-                self.update_zero_flag(data);
-                self.update_parity_flag(data);
-                self.set_register_or_memory_data(reg, data);
-                self.pc += 1;
-            }
+    #[inline]
+    pub fn make_address(val1: u8, val2: u8) -> usize {
+        Self::compose_to_u16(val1, val2) as usize
+    }
 
-            Opcode::MVI_A
-            | Opcode::MVI_B
-            | Opcode::MVI_C
-            | Opcode::MVI_D
-            | Opcode::MVI_E
-            | Opcode::MVI_H
-            | Opcode::MVI_L
-            | Opcode::MVI_M => {
-                let reg = opcode.get_dest_num();
-                let val = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.set_register_or_memory_data(reg, val);
-            }
+    #[inline]
+    fn compose_to_u16(val1: u8, val2: u8) -> u16 {
+        u16::from(val1) << 8 | u16::from(val2)
+    }
 
-            Opcode::RLC => {
-                self.pc += 1;
-                self.flag.set_carry_flag(self.acc & 0b1000_0000 != 0);
-                self.acc = self.acc.rotate_left(1);
-            }
+    #[inline]
+    fn decompose_to_u8(val: u16) -> (u8, u8) {
+        let res = ((val & 0xFF_00) >> 8) as u8;
+        let res2 = (val & 0x00_FF) as u8;
+        (res, res2)
+    }
 
-            Opcode::RRC => {
-                self.pc += 1;
-                self.flag.set_carry_flag(self.acc & 0b0000_00001 != 0);
-                self.acc = self.acc.rotate_right(1);
-            }
+    #[inline]
+    pub fn set_memory_address(&mut self, addr: u16) {
+        let (h, l) = Self::decompose_to_u8(addr);
+        self.registers[Register::H as usize] = h;
+        self.registers[Register::L as usize] = l;
+    }
 
-            Opcode::RAL => {
-                self.pc += 1;
-                self.acc = self.acc.rotate_left(1);
-                let b = self.acc | 0b0000_0001;
-                if self.flag.carry_flag() {
-                    self.acc |= 0b0000_0001;
-                } else {
-                    self.acc &= 0b1111_1110;
-                }
-                self.flag.set_carry_flag(b != 0);
-            }
+    #[inline]
+    fn memory_address(&self) -> usize {
+        Self::make_address(
+            self.registers[Register::H as usize],
+            self.registers[Register::L as usize],
+        )
+    }
 
-            Opcode::RAR => {
-                self.pc += 1;
-                self.acc = self.acc.rotate_right(1);
-                let b = self.acc & 0b1000_0000;
-                if self.flag.carry_flag() {
-                    self.acc |= 0b1000_0000;
-                } else {
-                    self.acc &= 0b0111_1111;
-                }
+    /// HL as an address, i.e. where the `M` operand points.
+    #[inline]
+    pub fn hl_addr(&self) -> usize {
+        self.memory_address()
+    }
 
-                self.flag.set_carry_flag(b != 0);
-            }
+    #[inline]
+    pub fn de_addr(&self) -> usize {
+        Self::make_address(
+            self.registers[Register::D as usize],
+            self.registers[Register::E as usize],
+        )
+    }
 
-            Opcode::DAD_B | Opcode::DAD_D | Opcode::DAD_H | Opcode::DAD_SP => {
-                if opcode.get_rp_num_2() != 0b11 {
-                    let reg1 = (opcode.get_rp_num_2() << 1) as usize;
-                    let reg2 = (opcode.get_rp_num_2() << 1) as usize + 1;
-                    let val1 = Self::compose_to_u16(self.registers[reg1], self.registers[reg2]);
-                    let l = self.registers[Register::L as usize];
-                    let h = self.registers[Register::H as usize];
-                    let val2 = Self::compose_to_u16(h, l);
-                    let res = val1.wrapping_add(val2);
-                    self.flag.set_carry_flag(val1 > 0xFFFF - val2);
-                    let (h, l) = Self::decompose_to_u8(res);
-                    self.registers[Register::H as usize] = h;
-                    self.registers[Register::L as usize] = l;
-                } else {
-                    let (h, l) = Self::decompose_to_u8(
-                        (self.memory_address() as u16).wrapping_add(self.sp),
-                    );
-                    self.registers[Register::H as usize] = h;
-                    self.registers[Register::L as usize] = l;
-                }
-                self.pc += 1;
-            }
+    #[inline]
+    pub fn bc_addr(&self) -> usize {
+        Self::make_address(
+            self.registers[Register::B as usize],
+            self.registers[Register::C as usize],
+        )
+    }
 
-            Opcode::DCX_B | Opcode::DCX_D | Opcode::DCX_H | Opcode::DCX_SP => {
-                if opcode.get_rp_num_2() != 0b11 {
-                    let reg1 = (opcode.get_rp_num_2() << 1) as usize;
-                    let reg2 = (opcode.get_rp_num_2() << 1) as usize + 1;
-                    let (val1, val2) = Self::dec(self.registers[reg1], self.registers[reg2]);
-                    self.registers[reg1] = val1;
-                    self.registers[reg2] = val2;
-                } else {
-                    self.sp = self.sp.wrapping_sub(1);
-                }
-                self.pc += 1;
-            }
+    /// Adds `val` to `pair` the way `DAD` adds to HL: only CY changes, set on
+    /// a carry out of bit 15. A helper for checking 16-bit routines, not an
+    /// instruction; it takes no cycles.
+    pub fn add16(&mut self, pair: RegisterPair, val: u16) {
+        let (res, carry) = self.pair(pair).overflowing_add(val);
+        self.flag.set_carry_flag(carry);
+        self.set_pair(pair, res);
+    }
 
-            Opcode::SHLD => {
-                let low_addr = self.data[self.pc() + 1];
-                let high_addr = self.data[self.pc() + 2];
-                let addr = Self::make_address(high_addr, low_addr);
-                self.pc += 3;
-                self.data[addr] = self.registers[Register::L as usize];
-                self.data[addr + 1] = self.registers[Register::H as usize];
-            }
+    /// Subtracts `val` from `pair`, setting only CY, on a borrow. The 8080 has
+    /// no such instruction; like `add16` this is a debugging helper.
+    pub fn sub16(&mut self, pair: RegisterPair, val: u16) {
+        let (res, borrow) = self.pair(pair).overflowing_sub(val);
+        self.flag.set_carry_flag(borrow);
+        self.set_pair(pair, res);
+    }
 
-            Opcode::LHLD => {
-                let low_addr = self.data[self.pc() + 1];
-                let high_addr = self.data[self.pc() + 2];
-                let addr = Self::make_address(high_addr, low_addr);
-                self.pc += 3;
-                self.registers[Register::L as usize] = self.data[addr];
-                self.registers[Register::H as usize] = self.data[addr + 1];
-            }
+    /// HL read as a signed 16-bit value, e.g. -1 for 0xFFFF.
+    #[inline]
+    pub fn hl_signed(&self) -> i16 {
+        self.pair(RegisterPair::HL) as i16
+    }
 
-            Opcode::CMA => {
-                self.pc += 1;
-                self.acc ^= 0b1111_1111;
-            }
+    /// BC read as a signed 16-bit value.
+    #[inline]
+    pub fn bc_signed(&self) -> i16 {
+        self.pair(RegisterPair::BC) as i16
+    }
 
-            Opcode::CMC => {
-                self.pc += 1;
-                self.flag.set_carry_flag(!self.flag.carry_flag());
-            }
+    /// DE read as a signed 16-bit value.
+    #[inline]
+    pub fn de_signed(&self) -> i16 {
+        self.pair(RegisterPair::DE) as i16
+    }
 
-            Opcode::DAA => {
-                self.pc += 1;
-                let low = self.acc & 0x0F;
+    /// Reads `M`, the byte HL points at.
+    #[inline]
+    pub fn read_at_hl(&self) -> u8 {
+        self.get_value(self.memory_address())
+    }
 
-                if low >= 10 {
-                    self.acc += 6;
-                    self.flag.set_auxiliary_carry_flag(true);
-                } else if self.flag.auxiliary_flag() {
-                    self.acc += 6;
-                    self.flag.set_auxiliary_carry_flag(false);
-                }
+    /// Writes `M`, the byte HL points at.
+    #[inline]
+    pub fn write_at_hl(&mut self, val: u8) {
+        self.set_value(self.memory_address(), val);
+    }
 
-                let high = (self.acc & 0xF0) >> 4;
+    #[inline]
+    fn register_or_memory_data(&mut self, reg: u8) -> u8 {
+        if reg == Register::Mem as u8 {
+            self.load(self.memory_address())
+        } else if reg == Register::Acc as u8 {
+            self.acc
+        } else {
+            self.reg(reg as usize)
+        }
+    }
 
-                if high >= 10 {
-                    self.acc = ((high - 10) << 4) | (self.acc & 0x0F);
-                    self.flag.set_carry_flag(true);
-                } else if self.flag.carry_flag() {
-                    self.acc = ((high + 6) << 4) | (self.acc & 0x0F);
-                }
+    #[inline]
+    fn set_register_or_memory_data(&mut self, reg: u8, data: u8) {
+        if reg == Register::Mem as u8 {
+            let addr = self.memory_address();
+            self.set_value(addr, data);
+        } else if reg == Register::Acc as u8 {
+            self.acc = data;
+        } else {
+            self.set_reg(reg as usize, data);
+        }
+    }
 
-                self.update_zero_flag(self.acc);
-                self.update_parity_flag(self.acc);
-                self.update_sign_flag(self.acc);
-            }
+    /// Reads `registers[i]`, where `i` is a register number that must not be
+    /// M or A.
+    #[inline]
+    fn reg(&self, i: usize) -> u8 {
+        debug_assert!(i < 6, "register {} is not in `registers`", i);
+        self.registers[i]
+    }
 
-            Opcode::STC => {
-                self.pc += 1;
-                self.flag.set_carry_flag(true);
-            }
+    /// Writes `registers[i]`, with the same check as `reg`.
+    #[inline]
+    fn set_reg(&mut self, i: usize, val: u8) {
+        debug_assert!(i < 6, "register {} is not in `registers`", i);
+        self.registers[i] = val;
+    }
 
-            Opcode::IN => {
-                let _device = self.data[self.pc() + 1];
-                self.pc += 2;
-                // todo
-            }
+    /// The little-endian word at `addr` and `addr + 1`, wrapping at 0xFFFF.
+    #[inline]
+    fn word_at(&self, addr: u16) -> u16 {
+        let high = self.get_value(usize::from(addr.wrapping_add(1)));
+        Self::compose_to_u16(high, self.get_value(usize::from(addr)))
+    }
 
-            Opcode::OUT => {
-                let _device = self.data[self.pc() + 1];
-                self.pc += 2;
-                // todo
-            }
+    /// The byte after the opcode at PC.
+    #[inline]
+    fn operand_u8(&self) -> u8 {
+        self.get_value(usize::from(self.pc.wrapping_add(1)))
+    }
 
-            Opcode::HLT => {
-                self.pc += 1;
-                self.halted = true;
-            }
+    /// The word after the opcode at PC.
+    #[inline]
+    fn operand_u16(&self) -> u16 {
+        self.word_at(self.pc.wrapping_add(1))
+    }
 
-            Opcode::EI => {
-                self.pc += 1;
-                self.interrupted = true;
-            }
+    /// The three bytes from PC, enough for any instruction.
+    fn instruction_bytes(&self) -> [u8; 3] {
+        let byte = |i| self.get_value(usize::from(self.pc.wrapping_add(i)));
+        [byte(0), byte(1), byte(2)]
+    }
 
-            Opcode::DI => {
-                self.pc += 1;
-                self.interrupted = false;
-            }
+    /// Whether the operands of `opcode` at PC are in memory. With a full 64 KiB
+    /// they always are, since they wrap around to address 0.
+    fn operands_fit(&self, opcode: Opcode) -> bool {
+        self.data.len() >= 0x10000 || self.pc() + opcode.length() <= self.data.len()
+    }
 
-            Opcode::RST_0
-            | Opcode::RST_1
-            | Opcode::RST_2
-            | Opcode::RST_3
-            | Opcode::RST_4
-            | Opcode::RST_5
-            | Opcode::RST_6
-            | Opcode::RST_7 => {
-                self.pc += 1;
-                self.stack_push(self.pc);
-                self.pc = u16::from(opcode as u8 & 0b0011_1000);
-            }
+    #[inline]
+    fn set_jump_pc(&mut self) {
+        self.pc = self.word_at(self.pc.wrapping_sub(2));
+    }
 
-            Opcode::XCHG => {
-                self.pc += 1;
-                let d = self.registers[Register::D as usize];
-                let e = self.registers[Register::E as usize];
-                self.registers[Register::D as usize] = self.registers[Register::H as usize];
-                self.registers[Register::E as usize] = self.registers[Register::L as usize];
-                self.registers[Register::H as usize] = d;
-                self.registers[Register::L as usize] = e;
-            }
+    /// The `registers` slots of the pair `rp` (B, D or H), high register first.
+    #[inline]
+    fn reg_slots(rp: u8) -> (usize, usize) {
+        debug_assert!(rp < 3, "register pair {} has no slots in `registers`", rp);
+        let high = (rp as usize) << 1;
+        (high, high + 1)
+    }
 
-            Opcode::XTHL => {
-                self.pc += 1;
-                let l = self.stack_pop_u8();
-                let h = self.stack_pop_u8();
-                self.stack_push_u8(self.registers[Register::H as usize]);
-                self.stack_push_u8(self.registers[Register::L as usize]);
-                self.registers[Register::H as usize] = h;
-                self.registers[Register::L as usize] = l;
+    /// The value of `rp`, e.g. `0x1234` when B is 0x12 and C is 0x34.
+    #[inline]
+    pub fn pair(&self, rp: RegisterPair) -> u16 {
+        match rp {
+            RegisterPair::SP => self.sp,
+            _ => {
+                let (high, low) = Self::reg_slots(rp as u8);
+                Self::compose_to_u16(self.reg(high), self.reg(low))
             }
+        }
+    }
 
-            Opcode::PUSH_B | Opcode::PUSH_D | Opcode::PUSH_H | Opcode::PUSH_PSW => {
-                self.pc += 1;
-                if opcode.get_rp_num_2() != 0b11 {
-                    let reg1 = opcode.get_rp_num_2() << 1;
-                    let reg2 = (opcode.get_rp_num_2() << 1) + 1;
-                    self.stack_push_u8(self.registers[reg1 as usize]);
-                    self.stack_push_u8(self.registers[reg2 as usize]);
-                } else {
-                    self.stack_push_u8(self.acc);
-                    self.stack_push_u8(self.flag.value());
-                }
+    /// Sets `rp` to `val`, high byte into the first register of the pair.
+    #[inline]
+    pub fn set_pair(&mut self, rp: RegisterPair, val: u16) {
+        match rp {
+            RegisterPair::SP => self.sp = val,
+            _ => {
+                let (high, low) = Self::reg_slots(rp as u8);
+                let (val1, val2) = Self::decompose_to_u8(val);
+                self.set_reg(high, val1);
+                self.set_reg(low, val2);
             }
+        }
+    }
 
-            Opcode::POP_B | Opcode::POP_D | Opcode::POP_H | Opcode::POP_PSW => {
-                self.pc += 1;
-                if opcode.get_rp_num_2() != 0b11 {
-                    let reg1 = opcode.get_rp_num_2() << 1;
-                    let reg2 = (opcode.get_rp_num_2() << 1) + 1;
-                    self.registers[reg2 as usize] = self.stack_pop_u8();
-                    self.registers[reg1 as usize] = self.stack_pop_u8();
-                } else {
-                    let flag = self.stack_pop_u8();
-                    self.flag.set_value(flag);
-                    self.acc = self.stack_pop_u8();
-                }
-            }
+    #[inline]
+    fn update_zero_flag(&mut self, val: u8) {
+        self.flag.set_zero_flag(val == 0);
+    }
 
-            Opcode::ADI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.flag
-                    .set_carry_flag(u16::from(self.acc) + u16::from(data) > 0xff);
-                self.update_aux_flag(self.acc, data);
-                self.acc = self.acc.wrapping_add(data);
-                self.update_parity_flag(self.acc);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-            }
+    #[inline]
+    fn update_sign_flag(&mut self, val: u8) {
+        self.flag.set_sign_flag(val & 0b1000_0000 != 0);
+    }
 
-            Opcode::ACI => {
-                let carry = self.flag.carry_flag() as u8;
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.update_carry_flag_with_carry(self.acc, data, carry);
-                self.update_aux_flag_with_carry(self.acc, data, carry);
-                self.acc = self.acc.wrapping_add(data).wrapping_add(carry);
-                self.update_parity_flag(self.acc);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-            }
+    /// `a + b + carry` and the flags `ADD`, `ADC`, `ADI` and `ACI` leave.
+    pub fn alu_add(a: u8, b: u8, carry: bool) -> (u8, Flag) {
+        let carry = u8::from(carry);
+        let res = a.wrapping_add(b).wrapping_add(carry);
+        let mut flag = Self::result_flags(res);
+        flag.set_carry_flag(u16::from(a) + u16::from(b) + u16::from(carry) > 0xFF);
+        flag.set_auxiliary_carry_flag((a & 0x0F) + (b & 0x0F) + carry > 0x0F);
+        (res, flag)
+    }
 
-            Opcode::SUI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.flag.set_carry_flag(self.acc < data);
-                self.flag
-                    .set_auxiliary_carry_flag((self.acc & 0b0000_1111) >= (data & 0b0000_1111));
-                self.acc = self.acc.wrapping_sub(data);
-                self.update_parity_flag(self.acc);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-            }
+    /// `a - b - borrow` and the flags `SUB`, `SBB`, `SUI` and `SBI` leave;
+    /// `CMP` and `CPI` keep only the flags.
+    ///
+    /// CY is the borrow. The 8080 subtracts by computing `a + !b + !borrow`,
+    /// so AC is the carry out of bit 3 of that sum,
+    /// `(a & 0xF) + (!b & 0xF) + !borrow > 0xF`, which is
+    /// `(a & 0xF) >= (b & 0xF) + borrow`: set when the low nibble needs no borrow.
+    pub fn alu_sub(a: u8, b: u8, borrow: bool) -> (u8, Flag) {
+        let borrow = u8::from(borrow);
+        let res = a.wrapping_sub(b).wrapping_sub(borrow);
+        let mut flag = Self::result_flags(res);
+        flag.set_carry_flag(u16::from(a) < u16::from(b) + u16::from(borrow));
+        flag.set_auxiliary_carry_flag((a & 0x0F) >= (b & 0x0F) + borrow);
+        (res, flag)
+    }
 
-            Opcode::SBI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                let carry = self.flag.carry_flag() as u8;
-
-                self.flag
-                    .set_carry_flag(u16::from(self.acc) < u16::from(data) + u16::from(carry));
-                self.flag.set_auxiliary_carry_flag(
-                    (self.acc & 0b0000_1111) < (data & 0b0000_1111) + carry,
-                );
-                self.acc = self.acc.wrapping_sub(data).wrapping_sub(carry);
-                self.update_parity_flag(self.acc);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-            }
+    /// The flags `CMP` leaves after comparing `a` (the accumulator) with `b`,
+    /// e.g. CY when `a < b` and Z when they are equal.
+    pub fn compare(a: u8, b: u8) -> Flag {
+        Self::alu_sub(a, b, false).1
+    }
 
-            Opcode::ANI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.acc &= data;
-                self.flag.set_carry_flag(false);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-                self.update_parity_flag(self.acc);
-            }
+    /// `a & b` and the flags of `ANA` and `ANI`, which set AC to the OR of
+    /// bit 3 of the operands.
+    pub fn alu_and(a: u8, b: u8) -> (u8, Flag) {
+        let res = a & b;
+        let mut flag = Self::result_flags(res);
+        flag.set_auxiliary_carry_flag((a | b) & 0x08 != 0);
+        (res, flag)
+    }
 
-            Opcode::XRI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.acc ^= data;
-                self.flag.set_carry_flag(false);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-                self.update_parity_flag(self.acc);
-            }
+    /// `a ^ b` and the flags of `XRA` and `XRI`.
+    pub fn alu_xor(a: u8, b: u8) -> (u8, Flag) {
+        let res = a ^ b;
+        (res, Self::result_flags(res))
+    }
 
-            Opcode::ORI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.acc |= data;
-                self.flag.set_carry_flag(false);
-                self.update_zero_flag(self.acc);
-                self.update_sign_flag(self.acc);
-                self.update_parity_flag(self.acc);
-            }
+    /// `a | b` and the flags of `ORA` and `ORI`.
+    pub fn alu_or(a: u8, b: u8) -> (u8, Flag) {
+        let res = a | b;
+        (res, Self::result_flags(res))
+    }
 
-            Opcode::CPI => {
-                let data = self.data[self.pc() + 1];
-                self.pc += 2;
-                self.flag.set_carry_flag(self.acc < data);
-                self.flag
-                    .set_auxiliary_carry_flag((self.acc & 0b0000_1111) < (data & 0b0000_1111));
-                let res = self.acc.wrapping_sub(data);
-                self.update_parity_flag(res);
-                self.update_zero_flag(res);
-                self.update_sign_flag(res);
-            }
+    /// Chooses between the 8080's half-carry rules and the simplified ones
+    /// that some other emulators use; strict is the default.
+    ///
+    /// When lenient, `SUB`, `SBB`, `SUI`, `SBI`, `CMP` and `CPI` set AC when
+    /// the whole minuend is at least the subtrahend plus borrow, and `ANA` and
+    /// `ANI` clear AC. Other instructions, including `DAA`, are unaffected.
+    pub fn set_strict_flags(&mut self, strict: bool) {
+        self.strict_flags = strict;
+    }
 
-            Opcode::JMP => {
-                self.pc += 3;
-                self.set_jump_pc();
-            }
+    /// **Not 8080 behaviour.** When false, `ADD`, `ADC`, `SUB`, `SBB`, `ANA`,
+    /// `XRA`, `ORA` and their immediate forms only update CY and AC, leaving
+    /// S, Z and P as they were; the default is true.
+    ///
+    /// This trades correctness for speed in programs that never test those
+    /// flags after an accumulator operation, e.g. ROMs that only move data.
+    /// Any program that does, including one that branches on `ORA A`, will
+    /// behave differently. `CMP`, `CPI`, `INR`, `DCR` and `DAA` always update
+    /// every flag, since they are how loops and comparisons are written.
+    pub fn set_update_flags(&mut self, update: bool) {
+        self.update_flags = update;
+    }
 
-            Opcode::JC => {
-                self.pc += 3;
-                if self.flag.carry_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    /// Stores the flags an accumulator operation left, honouring `set_update_flags`.
+    #[inline]
+    fn set_alu_flags(&mut self, flag: Flag) {
+        if self.update_flags {
+            self.flag = flag;
+        } else {
+            let kept = self.flag.value() & !0b0001_0001;
+            self.flag = Flag::new(kept | flag.value() & 0b0001_0001);
+        }
+    }
 
-            Opcode::JNC => {
-                self.pc += 3;
-                if !self.flag.carry_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    #[inline]
+    fn sub_flags(&self, a: u8, b: u8, borrow: bool) -> (u8, Flag) {
+        let (res, mut flag) = Self::alu_sub(a, b, borrow);
+        if !self.strict_flags {
+            flag.set_auxiliary_carry_flag(u16::from(a) >= u16::from(b) + u16::from(borrow));
+        }
+        (res, flag)
+    }
 
-            Opcode::JZ => {
-                self.pc += 3;
-                if self.flag.zero_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    #[inline]
+    fn and_flags(&self, a: u8, b: u8) -> (u8, Flag) {
+        let (res, mut flag) = Self::alu_and(a, b);
+        if !self.strict_flags {
+            flag.set_auxiliary_carry_flag(false);
+        }
+        (res, flag)
+    }
 
-            Opcode::JNZ => {
-                self.pc += 3;
-                if !self.flag.zero_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    /// S, Z and P for `res`, with CY and AC clear.
+    #[inline]
+    fn result_flags(res: u8) -> Flag {
+        let mut flag = Flag::default();
+        flag.set_sign_flag(res & 0b1000_0000 != 0);
+        flag.set_zero_flag(res == 0);
+        flag.set_parity_flag(Flag::parity_of(res));
+        flag
+    }
 
-            Opcode::JM => {
-                self.pc += 3;
-                if self.flag.sign_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    #[inline]
+    fn update_add_overflow(&mut self, val1: u8, val2: u8, res: u8) {
+        self.add_overflowed = (val1 ^ res) & (val2 ^ res) & 0b1000_0000 != 0;
+    }
 
-            Opcode::JP => {
-                self.pc += 3;
-                if !self.flag.sign_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    #[inline]
+    fn update_sub_overflow(&mut self, val1: u8, val2: u8, res: u8) {
+        self.sub_overflowed = (val1 ^ val2) & (val1 ^ res) & 0b1000_0000 != 0;
+    }
 
-            Opcode::JPE => {
-                self.pc += 3;
-                if self.flag.parity_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    /// Whether the last `ADD`/`ADC`/`ADI`/`ACI` overflowed when its operands are
+    /// read as signed bytes, e.g. `0x7F + 1`.
+    ///
+    /// The 8080 has no overflow flag, so this is purely an introspection aid and
+    /// does not affect execution.
+    #[inline]
+    pub fn last_add_overflowed(&self) -> bool {
+        self.add_overflowed
+    }
 
-            Opcode::JPO => {
-                self.pc += 3;
-                if !self.flag.parity_flag() {
-                    self.set_jump_pc();
-                }
-            }
+    /// Like `last_add_overflowed`, for the last `SUB`/`SBB`/`SUI`/`SBI`/`CMP`/`CPI`.
+    #[inline]
+    pub fn last_sub_overflowed(&self) -> bool {
+        self.sub_overflowed
+    }
 
-            Opcode::CALL => {
-                self.pc += 3;
-                self.op_call();
-            }
+    #[inline]
+    fn update_parity_flag(&mut self, val: u8) {
+        self.flag.set_parity_flag(Flag::parity_of(val));
+    }
 
-            Opcode::CC => {
-                self.pc += 3;
-                if self.flag.carry_flag() {
-                    self.op_call();
-                }
-            }
+    #[inline]
+    fn stack_push_u8(&mut self, val: u8) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.set_value(self.sp(), val);
+    }
 
-            Opcode::CNC => {
-                self.pc += 3;
-                if !self.flag.carry_flag() {
-                    self.op_call();
-                }
-            }
+    #[inline]
+    fn stack_pop_u8(&mut self) -> u8 {
+        let res = self.load(self.sp());
+        self.sp = self.sp.wrapping_add(1);
+        res
+    }
 
-            Opcode::CZ => {
-                self.pc += 3;
-                if self.flag.zero_flag() {
-                    self.op_call();
-                }
-            }
+    /// What `XTHL` does: swaps L with the byte at SP and H with the byte at
+    /// SP+1, wrapping at 0xFFFF. SP ends where it started.
+    pub fn exchange_stack_hl(&mut self) {
+        let l = self.stack_pop_u8();
+        let h = self.stack_pop_u8();
+        self.stack_push_u8(self.registers[Register::H as usize]);
+        self.stack_push_u8(self.registers[Register::L as usize]);
+        self.registers[Register::H as usize] = h;
+        self.registers[Register::L as usize] = l;
+    }
 
-            Opcode::CNZ => {
-                self.pc += 3;
-                if !self.flag.zero_flag() {
-                    self.op_call();
-                }
-            }
+    #[inline]
+    pub fn stack_push(&mut self, val: u16) {
+        let (val1, val2) = Self::decompose_to_u8(val);
+        self.stack_push_u8(val1);
+        self.stack_push_u8(val2);
+    }
 
-            Opcode::CM => {
-                self.pc += 3;
-                if self.flag.sign_flag() {
-                    self.op_call();
-                }
-            }
+    #[inline]
+    pub fn stack_pop(&mut self) -> u16 {
+        let val1 = self.stack_pop_u8();
+        let val2 = self.stack_pop_u8();
+        Self::compose_to_u16(val2, val1)
+    }
 
-            Opcode::CP => {
-                self.pc += 3;
-                if !self.flag.sign_flag() {
-                    self.op_call();
-                }
-            }
+    /// Like `stack_push`, but fails instead of pushing below address 0 or
+    /// outside memory. SP is left alone on failure.
+    pub fn try_stack_push(&mut self, val: u16) -> Result<()> {
+        if usize::from(self.sp.wrapping_sub(2)) + 2 > self.data.len() {
+            return Err(Error::StackOverflow);
+        }
+        self.stack_push(val);
+        Ok(())
+    }
 
-            Opcode::CPE => {
-                self.pc += 3;
-                if self.flag.parity_flag() {
-                    self.op_call();
-                }
-            }
+    /// Like `stack_pop`, but fails instead of popping past the end of memory.
+    /// SP is left alone on failure.
+    pub fn try_stack_pop(&mut self) -> Result<u16> {
+        if self.sp() + 2 > self.data.len() {
+            return Err(Error::StackUnderflow);
+        }
+        Ok(self.stack_pop())
+    }
 
-            Opcode::CPO => {
-                self.pc += 3;
-                if !self.flag.parity_flag() {
-                    self.op_call();
+    /// Up to `depth` words from the top of the stack, most recently pushed
+    /// first, without popping them. Stops at the end of memory.
+    pub fn stack_frames(&self, depth: usize) -> Vec<u16> {
+        (0..depth)
+            .map(|i| self.sp() + 2 * i)
+            .take_while(|&addr| addr + 1 < self.data.len())
+            .map(|addr| self.read_u16(addr))
+            .collect()
+    }
+
+    #[inline]
+    fn op_return(&mut self) {
+        self.pc = self.stack_pop();
+    }
+
+    #[inline]
+    fn op_call(&mut self) {
+        let addr = self.word_at(self.pc.wrapping_sub(2));
+        self.stack_push(self.pc);
+        self.pc = addr;
+        if self.call_hooks.0.is_some() {
+            self.run_call_hook();
+        }
+    }
+
+    /// Runs the hook for the subroutine just called, if there is one, then
+    /// returns from it unless the hook moved PC.
+    fn run_call_hook(&mut self) {
+        let addr = self.pc;
+        let mut hook = match self
+            .call_hooks
+            .0
+            .as_mut()
+            .and_then(|hooks| hooks.remove(&addr))
+        {
+            Some(hook) => hook,
+            None => return,
+        };
+        hook(self);
+        // the hook may have installed a replacement for itself
+        self.call_hooks
+            .0
+            .get_or_insert_with(HashMap::new)
+            .entry(addr)
+            .or_insert(hook);
+        if self.pc == addr {
+            self.pc = self.stack_pop();
+        }
+    }
+
+    /// Whether the CPU is stopped by `HLT`.
+    ///
+    /// A halted CPU waits for an interrupt: `run_once` does nothing else until
+    /// one is taken, which wakes it and jumps to the interrupt address. After
+    /// `DI` none can be taken, so it stays halted.
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Forcibly leaves the halted state without an interrupt.
+    ///
+    /// `HLT` has already advanced PC past itself, so the next `run_once`
+    /// executes the instruction following it. This is a tooling escape hatch;
+    /// real hardware can only leave `HLT` through an interrupt or a reset.
+    #[inline]
+    pub fn resume(&mut self) {
+        self.halted = false;
+    }
+
+    /// Total T-states executed so far, including any wait states.
+    #[inline]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Adds `cycles` T-states during which the CPU is kept off the bus, e.g.
+    /// by a DMA transfer.
+    #[inline]
+    pub fn steal_cycles(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    /// Instructions executed by `run_once` so far.
+    #[inline]
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Interrupts taken so far.
+    #[inline]
+    pub fn interrupts_serviced(&self) -> u64 {
+        self.interrupts_serviced
+    }
+
+    /// Like the RESET pin: PC goes back to 0, the CPU leaves `HLT`, pending
+    /// interrupts are dropped and the counters restart. Registers, SP and
+    /// memory are kept.
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.clear_control_state();
+        self.cycles = 0;
+        self.instructions = 0;
+        self.interrupts_serviced = 0;
+        self.halted_by_write = false;
+    }
+
+    /// The part of `reset` that leaves PC and the counters alone: the CPU
+    /// leaves `HLT`, interrupts are enabled and any pending request is
+    /// dropped. Registers, flags and memory are kept.
+    pub fn clear_control_state(&mut self) {
+        self.halted = false;
+        self.interrupted = true;
+        self.interrupted_addr = 0;
+        self.pending_interrupt = false;
+    }
+
+    /// Whether the conditional jump, call or return `opcode` would branch
+    /// with the current flags, or `None` if it is not conditional.
+    #[inline(always)]
+    fn branch_taken(&self, opcode: Opcode) -> Option<bool> {
+        let op = u8::from(opcode);
+        // Rcc, Jcc and Ccc are 11ccc000, 11ccc010 and 11ccc100
+        if op & 0b1100_0001 != 0b1100_0000 || op & 0b0000_0110 == 0b0000_0110 {
+            return None;
+        }
+        let taken = match opcode.get_dest_num() {
+            0 => !self.flag.zero_flag(),
+            1 => self.flag.zero_flag(),
+            2 => !self.flag.carry_flag(),
+            3 => self.flag.carry_flag(),
+            4 => !self.flag.parity_flag(),
+            5 => self.flag.parity_flag(),
+            6 => !self.flag.sign_flag(),
+            _ => self.flag.sign_flag(),
+        };
+        Some(taken)
+    }
+
+    /// Executes `opcode` and returns the T-states it took.
+    #[inline]
+    pub fn execute(&mut self, opcode: Opcode) -> u8 {
+        DISPATCH[usize::from(u8::from(opcode))](self)
+    }
+
+    /// The body of the `DISPATCH` handlers. `fetched` says whether `opcode`
+    /// came from memory at PC, to be logged as a fetch.
+    #[inline(always)]
+    fn execute_inline(&mut self, opcode: Opcode, fetched: bool) -> u8 {
+        if fetched && (self.mem_trace.is_some() || self.wait_state_hook.0.is_some()) {
+            for i in 0..opcode.length() as u16 {
+                let addr = usize::from(self.pc.wrapping_add(i));
+                if let Some(&val) = self.data.get(addr) {
+                    self.log_access(addr, val, AccessKind::Fetch);
                 }
             }
+        }
+        let (taken, not_taken) = opcode.cycles();
+        let cycles = if self.branch_taken(opcode) == Some(true) {
+            taken
+        } else {
+            not_taken
+        };
+        self.dispatch(opcode);
+        self.cycles += u64::from(cycles);
+        cycles
+    }
 
-            Opcode::RET => {
-                self.pc += 1;
-                self.op_return();
-            }
+    /// `execute` for an injected opcode that did not come from memory, so no
+    /// fetch is logged.
+    fn execute_injected(&mut self, opcode: Opcode) -> u8 {
+        self.execute_inline(opcode, false)
+    }
 
-            Opcode::RC => {
-                self.pc += 1;
-                if self.flag.carry_flag() {
-                    self.op_return();
-                }
+    /// Executes `opcode` as if it had been fetched from PC, e.g. for fuzzing.
+    ///
+    /// Operand bytes still come from memory at PC+1 and PC+2, and PC advances
+    /// past them as usual. Fails without executing anything if `opcode` is
+    /// undocumented or its operands run past the end of memory.
+    pub fn execute_byte(&mut self, opcode: u8) -> Result<()> {
+        let opcode = Opcode::decode(opcode)?;
+        if !self.operands_fit(opcode) {
+            return Err(Error::OutOfBounds);
+        }
+        self.execute(opcode);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn dispatch(&mut self, opcode: Opcode) {
+        match opcode {
+            Opcode::NOP => {
+                self.pc = self.pc.wrapping_add(1);
             }
 
-            Opcode::RNC => {
-                self.pc += 1;
-                if !self.flag.carry_flag() {
-                    self.op_return();
-                }
+            Opcode::LXI_B | Opcode::LXI_D | Opcode::LXI_H | Opcode::LXI_SP => {
+                let val = self.operand_u16();
+                self.set_pair(RegisterPair::from(opcode.get_rp_num_2()), val);
+                self.pc = self.pc.wrapping_add(3);
             }
 
-            Opcode::RZ => {
-                self.pc += 1;
-                if self.flag.zero_flag() {
-                    self.op_return();
-                }
+            Opcode::STAX_B | Opcode::STAX_D => {
+                self.pc = self.pc.wrapping_add(1);
+                let addr = self.pair(RegisterPair::from(opcode.get_rp_num()));
+                self.set_value(addr as usize, self.acc);
             }
-            Opcode::RNZ => {
-                self.pc += 1;
-                if !self.flag.zero_flag() {
-                    self.op_return();
-                }
+
+            Opcode::STA => {
+                let addr = self.operand_u16() as usize;
+                self.pc = self.pc.wrapping_add(3);
+                self.set_value(addr, self.acc);
             }
 
-            Opcode::RM => {
-                self.pc += 1;
-                if self.flag.sign_flag() {
-                    self.op_return();
-                }
+            Opcode::LDAX_B | Opcode::LDAX_D => {
+                self.pc = self.pc.wrapping_add(1);
+                let addr = self.pair(RegisterPair::from(opcode.get_rp_num()));
+                self.acc = self.load(addr as usize);
             }
 
-            Opcode::RP => {
-                self.pc += 1;
-                if !self.flag.sign_flag() {
-                    self.op_return();
-                }
+            Opcode::LDA => {
+                let addr = self.operand_u16() as usize;
+                self.pc = self.pc.wrapping_add(3);
+                self.acc = self.load(addr);
             }
 
-            Opcode::RPE => {
-                self.pc += 1;
-                if self.flag.parity_flag() {
-                    self.op_return();
+            Opcode::INX_B | Opcode::INX_D | Opcode::INX_H | Opcode::INX_SP => {
+                let rp = RegisterPair::from(opcode.get_rp_num_2());
+                self.set_pair(rp, self.pair(rp).wrapping_add(1));
+                self.pc = self.pc.wrapping_add(1);
+            }
+
+            Opcode::INR_A
+            | Opcode::INR_B
+            | Opcode::INR_C
+            | Opcode::INR_D
+            | Opcode::INR_E
+            | Opcode::INR_H
+            | Opcode::INR_L
+            | Opcode::INR_M => {
+                let reg = opcode.get_dest_num();
+                let data = self.register_or_memory_data(reg);
+                self.flag.set_auxiliary_carry_flag(data & 0b0000_1111 == 15);
+                let data = data.wrapping_add(1);
+                self.update_sign_flag(data);
+                self.update_zero_flag(data);
+                self.update_parity_flag(data);
+                self.set_register_or_memory_data(reg, data);
+                self.pc = self.pc.wrapping_add(1);
+            }
+
+            Opcode::DCR_A
+            | Opcode::DCR_B
+            | Opcode::DCR_C
+            | Opcode::DCR_D
+            | Opcode::DCR_E
+            | Opcode::DCR_H
+            | Opcode::DCR_L
+            | Opcode::DCR_M => {
+                let reg = opcode.get_dest_num();
+                let data = self.register_or_memory_data(reg);
+                self.flag.set_auxiliary_carry_flag(data & 0b0000_1111 == 15);
+                let data = data.wrapping_sub(1);
+                self.update_sign_flag(data);
+                self.update_zero_flag(data);
+                self.update_parity_flag(data);
+                self.set_register_or_memory_data(reg, data);
+                self.pc = self.pc.wrapping_add(1);
+            }
+
+            Opcode::MVI_A
+            | Opcode::MVI_B
+            | Opcode::MVI_C
+            | Opcode::MVI_D
+            | Opcode::MVI_E
+            | Opcode::MVI_H
+            | Opcode::MVI_L
+            | Opcode::MVI_M => {
+                let reg = opcode.get_dest_num();
+                let val = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                self.set_register_or_memory_data(reg, val);
+            }
+
+            Opcode::RLC => {
+                self.pc = self.pc.wrapping_add(1);
+                self.flag.set_carry_flag(self.acc & HIGH_BIT != 0);
+                self.acc = self.acc.rotate_left(1);
+            }
+
+            Opcode::RRC => {
+                self.pc = self.pc.wrapping_add(1);
+                self.flag.set_carry_flag(self.acc & LOW_BIT != 0);
+                self.acc = self.acc.rotate_right(1);
+            }
+
+            Opcode::RAL => {
+                self.pc = self.pc.wrapping_add(1);
+                let carry = self.acc & HIGH_BIT != 0;
+                self.acc <<= 1;
+                if self.flag.carry_flag() {
+                    self.acc |= LOW_BIT;
                 }
+                self.flag.set_carry_flag(carry);
             }
 
-            Opcode::RPO => {
-                self.pc += 1;
-                if !self.flag.parity_flag() {
-                    self.op_return();
+            Opcode::RAR => {
+                self.pc = self.pc.wrapping_add(1);
+                let carry = self.acc & LOW_BIT != 0;
+                self.acc >>= 1;
+                if self.flag.carry_flag() {
+                    self.acc |= HIGH_BIT;
                 }
+                self.flag.set_carry_flag(carry);
             }
 
-            Opcode::SPHL => {
-                self.pc += 1;
-                self.sp = self.memory_address() as u16;
+            Opcode::DAD_B | Opcode::DAD_D | Opcode::DAD_H | Opcode::DAD_SP => {
+                let val = self.pair(RegisterPair::from(opcode.get_rp_num_2()));
+                self.add16(RegisterPair::HL, val);
+                self.pc = self.pc.wrapping_add(1);
+            }
+
+            Opcode::DCX_B | Opcode::DCX_D | Opcode::DCX_H | Opcode::DCX_SP => {
+                let rp = RegisterPair::from(opcode.get_rp_num_2());
+                self.set_pair(rp, self.pair(rp).wrapping_sub(1));
+                self.pc = self.pc.wrapping_add(1);
+            }
+
+            Opcode::SHLD => {
+                let addr = self.operand_u16() as usize;
+                self.pc = self.pc.wrapping_add(3);
+                self.write_u16(addr, self.memory_address() as u16);
+            }
+
+            Opcode::LHLD => {
+                let addr = self.operand_u16() as usize;
+                self.pc = self.pc.wrapping_add(3);
+                let low = self.load(addr);
+                let high = self.load(addr + 1);
+                self.set_memory_address(Self::compose_to_u16(high, low));
+            }
+
+            Opcode::CMA => {
+                self.pc = self.pc.wrapping_add(1);
+                self.acc ^= 0b1111_1111;
             }
 
-            other => {
-                // other instruction's length is 8 bits
-                self.pc += 1;
+            Opcode::CMC => {
+                self.pc = self.pc.wrapping_add(1);
+                self.flag.set_carry_flag(!self.flag.carry_flag());
+            }
 
-                // mov
-                if (other as u8 & 0b1100_0000) == 1 << 6 {
-                    let dst = opcode.get_dest_num();
-                    let src = opcode.get_src_num();
+            Opcode::DAA => {
+                self.pc = self.pc.wrapping_add(1);
+                let low = self.acc & 0x0F;
 
-                    if dst != src {
-                        let src_data = self.register_or_memory_data(src);
-                        self.set_register_or_memory_data(dst, src_data);
-                    }
+                if low >= 10 {
+                    self.acc = self.acc.wrapping_add(6);
+                    self.flag.set_auxiliary_carry_flag(true);
+                } else if self.flag.auxiliary_flag() {
+                    self.acc = self.acc.wrapping_add(6);
+                    self.flag.set_auxiliary_carry_flag(false);
                 }
 
-                let alu = other as u8 & 0b1111_1000;
-                let reg = opcode.get_src_num();
-                let data = self.register_or_memory_data(reg);
-                // Condition bits affected: Carry, Sign, Zero, Parity, Auxiliary Carry
-
-                // add
-                if alu == 0b1000_0000 {
-                    self.update_aux_flag(self.acc, data);
-                    self.flag
-                        .set_carry_flag(u16::from(self.acc) + u16::from(data) > 0xff);
-                    self.acc = self.acc.wrapping_add(data);
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+                let high = (self.acc & 0xF0) >> 4;
+
+                if high >= 10 {
+                    self.acc = ((high - 10) << 4) | (self.acc & 0x0F);
+                    self.flag.set_carry_flag(true);
+                } else if self.flag.carry_flag() {
+                    self.acc = ((high + 6) << 4) | (self.acc & 0x0F);
+                }
+
+                self.update_zero_flag(self.acc);
+                self.update_parity_flag(self.acc);
+                self.update_sign_flag(self.acc);
+            }
+
+            Opcode::STC => {
+                self.pc = self.pc.wrapping_add(1);
+                self.flag.set_carry_flag(true);
+            }
+
+            Opcode::IN => {
+                let port = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let queued = self
+                    .input_queue
+                    .get_mut(&port)
+                    .and_then(VecDeque::pop_front);
+                self.acc = match (queued, self.device.0.as_mut()) {
+                    (Some(val), _) => val,
+                    (None, Some(device)) if device.is_mapped(port) => device.input(port),
+                    _ => self.open_bus,
+                };
+            }
+
+            Opcode::OUT => {
+                let port = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                if let Some(device) = self.device.0.as_mut() {
+                    device.output(port, self.acc);
+                }
+            }
+
+            Opcode::HLT => {
+                self.pc = self.pc.wrapping_add(1);
+                self.halted = true;
+            }
+
+            Opcode::EI => {
+                self.pc = self.pc.wrapping_add(1);
+                self.interrupted = true;
+            }
+
+            Opcode::DI => {
+                self.pc = self.pc.wrapping_add(1);
+                self.interrupted = false;
+            }
+
+            Opcode::RST_0
+            | Opcode::RST_1
+            | Opcode::RST_2
+            | Opcode::RST_3
+            | Opcode::RST_4
+            | Opcode::RST_5
+            | Opcode::RST_6
+            | Opcode::RST_7 => {
+                self.pc = self.pc.wrapping_add(1);
+                self.stack_push(self.pc);
+                self.pc = u16::from(opcode as u8 & 0b0011_1000);
+                if self.call_hooks.0.is_some() {
+                    self.run_call_hook();
                 }
-                // adc
-                else if alu == 0b1000_1000 {
-                    let carry = self.flag.carry_flag() as u8;
-                    self.update_aux_flag_with_carry(self.acc, data, carry);
-                    self.update_carry_flag_with_carry(self.acc, data, carry);
-                    self.acc = self.acc.wrapping_add(data).wrapping_add(carry);
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+            }
+
+            Opcode::XCHG => {
+                self.pc = self.pc.wrapping_add(1);
+                let d = self.registers[Register::D as usize];
+                let e = self.registers[Register::E as usize];
+                self.registers[Register::D as usize] = self.registers[Register::H as usize];
+                self.registers[Register::E as usize] = self.registers[Register::L as usize];
+                self.registers[Register::H as usize] = d;
+                self.registers[Register::L as usize] = e;
+            }
+
+            Opcode::XTHL => {
+                self.pc = self.pc.wrapping_add(1);
+                self.exchange_stack_hl();
+            }
+
+            Opcode::PUSH_B | Opcode::PUSH_D | Opcode::PUSH_H | Opcode::PUSH_PSW => {
+                self.pc = self.pc.wrapping_add(1);
+                if opcode.get_rp_num_2() != 0b11 {
+                    self.stack_push(self.pair(RegisterPair::from(opcode.get_rp_num_2())));
+                } else {
+                    self.stack_push_u8(self.acc);
+                    self.stack_push_u8(self.flag.value());
                 }
-                // sub
-                else if alu == 0b1001_0000 {
-                    self.flag.set_auxiliary_carry_flag(self.acc >= data);
-                    self.flag.set_carry_flag(self.acc < data);
-                    self.acc = self.acc.wrapping_sub(data);
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+            }
+
+            Opcode::POP_B | Opcode::POP_D | Opcode::POP_H | Opcode::POP_PSW => {
+                self.pc = self.pc.wrapping_add(1);
+                if opcode.get_rp_num_2() != 0b11 {
+                    let val = self.stack_pop();
+                    self.set_pair(RegisterPair::from(opcode.get_rp_num_2()), val);
+                } else {
+                    let flag = self.stack_pop_u8();
+                    self.flag.set_value(flag);
+                    self.acc = self.stack_pop_u8();
                 }
-                // sbb
-                else if alu == 0b1001_1000 {
-                    let carry = self.flag.carry_flag() as u8;
-                    self.flag.set_auxiliary_carry_flag(
-                        (self.acc & 0b0000_1111) >= (data & 0b0000_1111) + carry,
-                    );
-
-                    let data = data.wrapping_add(carry);
-                    self.flag.set_carry_flag(self.acc < data);
-                    self.acc = self.acc.wrapping_sub(data);
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+            }
+
+            Opcode::ADI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = Self::alu_add(self.acc, data, false);
+                self.update_add_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::ACI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = Self::alu_add(self.acc, data, self.flag.carry_flag());
+                self.update_add_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::SUI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = self.sub_flags(self.acc, data, false);
+                self.update_sub_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::SBI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = self.sub_flags(self.acc, data, self.flag.carry_flag());
+                self.update_sub_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::ANI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = self.and_flags(self.acc, data);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::XRI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = Self::alu_xor(self.acc, data);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::ORI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = Self::alu_or(self.acc, data);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::CPI => {
+                let data = self.operand_u8();
+                self.pc = self.pc.wrapping_add(2);
+                let (res, flag) = self.sub_flags(self.acc, data, false);
+                self.update_sub_overflow(self.acc, data, res);
+                self.flag = flag;
+            }
+
+            Opcode::JMP => {
+                self.pc = self.pc.wrapping_add(3);
+                self.set_jump_pc();
+            }
+
+            Opcode::JC => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.carry_flag() {
+                    self.set_jump_pc();
                 }
-                // ana
-                else if alu == 0b1010_0000 {
-                    self.flag.set_carry_flag(false);
-                    self.flag
-                        .set_auxiliary_carry_flag(((self.acc | data) & 0x08) != 0);
-                    self.acc &= data;
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+            }
+
+            Opcode::JNC => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.carry_flag() {
+                    self.set_jump_pc();
                 }
-                // xra
-                else if alu == 0b1010_1000 {
-                    self.acc ^= data;
-                    self.flag.set_carry_flag(false);
-                    self.flag.set_auxiliary_carry_flag(false);
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+            }
+
+            Opcode::JZ => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.zero_flag() {
+                    self.set_jump_pc();
                 }
-                // ora
-                else if alu == 0b1011_0000 {
-                    self.acc |= data;
-                    self.flag.set_carry_flag(false);
-                    self.flag.set_auxiliary_carry_flag(false);
-                    self.update_zero_flag(self.acc);
-                    self.update_sign_flag(self.acc);
-                    self.update_parity_flag(self.acc);
+            }
+
+            Opcode::JNZ => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.zero_flag() {
+                    self.set_jump_pc();
                 }
-                // cmp
-                else if alu == 0b1011_1000 {
-                    self.flag.set_auxiliary_carry_flag(self.acc >= data);
-                    self.flag.set_carry_flag(self.acc < data);
-                    let res = self.acc.wrapping_sub(data);
-                    self.update_zero_flag(res);
-                    self.update_sign_flag(res);
-                    self.update_parity_flag(res);
+            }
+
+            Opcode::JM => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.sign_flag() {
+                    self.set_jump_pc();
                 }
             }
-        }
-    }
 
-    pub fn interrupt(&mut self) {
-        // todo
-        if self.interrupted {
-            self.interrupted = false;
-            self.stack_push(self.pc);
-        }
-    }
+            Opcode::JP => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.sign_flag() {
+                    self.set_jump_pc();
+                }
+            }
 
-    #[inline]
-    pub fn run_once(&mut self) {
-        if self.halted {
-            self.handle_interrupt();
-            return;
-        }
-//        dbg!(Opcode::from(self.data[self.pc()]));
-        self.execute(Opcode::from(self.data[self.pc()]));
-    }
+            Opcode::JPE => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.parity_flag() {
+                    self.set_jump_pc();
+                }
+            }
 
-    pub fn handle_interrupt(&mut self) {
-        if self.interrupted {
-            self.interrupted = false;
-            self.stack_push(self.pc);
-            self.pc = self.interrupted_addr;
-        }
+            Opcode::JPO => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.parity_flag() {
+                    self.set_jump_pc();
+                }
+            }
+
+            Opcode::CALL => {
+                self.pc = self.pc.wrapping_add(3);
+                self.op_call();
+            }
+
+            Opcode::CC => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.carry_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CNC => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.carry_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CZ => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.zero_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CNZ => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.zero_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CM => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.sign_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CP => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.sign_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CPE => {
+                self.pc = self.pc.wrapping_add(3);
+                if self.flag.parity_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::CPO => {
+                self.pc = self.pc.wrapping_add(3);
+                if !self.flag.parity_flag() {
+                    self.op_call();
+                }
+            }
+
+            Opcode::RET => {
+                self.pc = self.pc.wrapping_add(1);
+                self.op_return();
+            }
+
+            Opcode::RC => {
+                self.pc = self.pc.wrapping_add(1);
+                if self.flag.carry_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::RNC => {
+                self.pc = self.pc.wrapping_add(1);
+                if !self.flag.carry_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::RZ => {
+                self.pc = self.pc.wrapping_add(1);
+                if self.flag.zero_flag() {
+                    self.op_return();
+                }
+            }
+            Opcode::RNZ => {
+                self.pc = self.pc.wrapping_add(1);
+                if !self.flag.zero_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::RM => {
+                self.pc = self.pc.wrapping_add(1);
+                if self.flag.sign_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::RP => {
+                self.pc = self.pc.wrapping_add(1);
+                if !self.flag.sign_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::RPE => {
+                self.pc = self.pc.wrapping_add(1);
+                if self.flag.parity_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::RPO => {
+                self.pc = self.pc.wrapping_add(1);
+                if !self.flag.parity_flag() {
+                    self.op_return();
+                }
+            }
+
+            Opcode::SPHL => {
+                self.pc = self.pc.wrapping_add(1);
+                self.sp = self.memory_address() as u16;
+            }
+
+            Opcode::PCHL => {
+                self.pc = self.memory_address() as u16;
+            }
+
+            // MOV_MM would be 0x76, which is HLT
+            Opcode::MOV_BB
+            | Opcode::MOV_BC
+            | Opcode::MOV_BD
+            | Opcode::MOV_BE
+            | Opcode::MOV_BH
+            | Opcode::MOV_BL
+            | Opcode::MOV_BM
+            | Opcode::MOV_BA
+            | Opcode::MOV_CB
+            | Opcode::MOV_CC
+            | Opcode::MOV_CD
+            | Opcode::MOV_CE
+            | Opcode::MOV_CH
+            | Opcode::MOV_CL
+            | Opcode::MOV_CM
+            | Opcode::MOV_CA
+            | Opcode::MOV_DB
+            | Opcode::MOV_DC
+            | Opcode::MOV_DD
+            | Opcode::MOV_DE
+            | Opcode::MOV_DH
+            | Opcode::MOV_DL
+            | Opcode::MOV_DM
+            | Opcode::MOV_DA
+            | Opcode::MOV_EB
+            | Opcode::MOV_EC
+            | Opcode::MOV_ED
+            | Opcode::MOV_EE
+            | Opcode::MOV_EH
+            | Opcode::MOV_EL
+            | Opcode::MOV_EM
+            | Opcode::MOV_EA
+            | Opcode::MOV_HB
+            | Opcode::MOV_HC
+            | Opcode::MOV_HD
+            | Opcode::MOV_HE
+            | Opcode::MOV_HH
+            | Opcode::MOV_HL
+            | Opcode::MOV_HM
+            | Opcode::MOV_HA
+            | Opcode::MOV_LB
+            | Opcode::MOV_LC
+            | Opcode::MOV_LD
+            | Opcode::MOV_LE
+            | Opcode::MOV_LH
+            | Opcode::MOV_LL
+            | Opcode::MOV_LM
+            | Opcode::MOV_LA
+            | Opcode::MOV_MB
+            | Opcode::MOV_MC
+            | Opcode::MOV_MD
+            | Opcode::MOV_ME
+            | Opcode::MOV_MH
+            | Opcode::MOV_ML
+            | Opcode::MOV_MA
+            | Opcode::MOV_AB
+            | Opcode::MOV_AC
+            | Opcode::MOV_AD
+            | Opcode::MOV_AE
+            | Opcode::MOV_AH
+            | Opcode::MOV_AL
+            | Opcode::MOV_AM
+            | Opcode::MOV_AA => {
+                self.pc = self.pc.wrapping_add(1);
+                let dst = opcode.get_dest_num();
+                let src = opcode.get_src_num();
+
+                if dst != src {
+                    let src_data = self.register_or_memory_data(src);
+                    self.set_register_or_memory_data(dst, src_data);
+                }
+            }
+
+            Opcode::ADD_A
+            | Opcode::ADD_B
+            | Opcode::ADD_C
+            | Opcode::ADD_D
+            | Opcode::ADD_E
+            | Opcode::ADD_H
+            | Opcode::ADD_L
+            | Opcode::ADD_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = Self::alu_add(self.acc, data, false);
+                self.update_add_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::ADC_A
+            | Opcode::ADC_B
+            | Opcode::ADC_C
+            | Opcode::ADC_D
+            | Opcode::ADC_E
+            | Opcode::ADC_H
+            | Opcode::ADC_L
+            | Opcode::ADC_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = Self::alu_add(self.acc, data, self.flag.carry_flag());
+                self.update_add_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::SUB_A
+            | Opcode::SUB_B
+            | Opcode::SUB_C
+            | Opcode::SUB_D
+            | Opcode::SUB_E
+            | Opcode::SUB_H
+            | Opcode::SUB_L
+            | Opcode::SUB_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = self.sub_flags(self.acc, data, false);
+                self.update_sub_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::SBB_A
+            | Opcode::SBB_B
+            | Opcode::SBB_C
+            | Opcode::SBB_D
+            | Opcode::SBB_E
+            | Opcode::SBB_H
+            | Opcode::SBB_L
+            | Opcode::SBB_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = self.sub_flags(self.acc, data, self.flag.carry_flag());
+                self.update_sub_overflow(self.acc, data, res);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::ANA_A
+            | Opcode::ANA_B
+            | Opcode::ANA_C
+            | Opcode::ANA_D
+            | Opcode::ANA_E
+            | Opcode::ANA_H
+            | Opcode::ANA_L
+            | Opcode::ANA_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = self.and_flags(self.acc, data);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::XRA_A
+            | Opcode::XRA_B
+            | Opcode::XRA_C
+            | Opcode::XRA_D
+            | Opcode::XRA_E
+            | Opcode::XRA_H
+            | Opcode::XRA_L
+            | Opcode::XRA_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = Self::alu_xor(self.acc, data);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::ORA_A
+            | Opcode::ORA_B
+            | Opcode::ORA_C
+            | Opcode::ORA_D
+            | Opcode::ORA_E
+            | Opcode::ORA_H
+            | Opcode::ORA_L
+            | Opcode::ORA_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = Self::alu_or(self.acc, data);
+                self.acc = res;
+                self.set_alu_flags(flag);
+            }
+
+            Opcode::CMP_A
+            | Opcode::CMP_B
+            | Opcode::CMP_C
+            | Opcode::CMP_D
+            | Opcode::CMP_E
+            | Opcode::CMP_H
+            | Opcode::CMP_L
+            | Opcode::CMP_M => {
+                self.pc = self.pc.wrapping_add(1);
+                let data = self.register_or_memory_data(opcode.get_src_num());
+                let (res, flag) = self.sub_flags(self.acc, data, false);
+                self.update_sub_overflow(self.acc, data, res);
+                self.flag = flag;
+            }
+        }
+    }
+
+    pub fn interrupt(&mut self) {
+        // todo
+        if self.interrupted {
+            self.interrupted = false;
+            self.stack_push(self.pc);
+        }
+    }
+
+    /// Writes one line per executed instruction to `w`, e.g.
+    /// `0100  C3 00 01  JMP $0100     A=00 F=02 BC=0000 DE=0000 HL=0000 SP=0000`.
+    ///
+    /// Output is buffered; it is flushed by `disable_trace` or when the CPU is dropped.
+    pub fn enable_trace<W: Write + 'static>(&mut self, w: W) {
+        self.trace.0 = Some(BufWriter::new(Box::new(w)));
+    }
+
+    pub fn disable_trace(&mut self) -> io::Result<()> {
+        match self.trace.0.take() {
+            Some(mut w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn write_trace(&mut self) {
+        let instruction = self.instruction_bytes();
+        let (text, len) = disassemble(&instruction, 0);
+        let bytes = instruction[..len]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let line = format!(
+            "{:04X}  {:<8}  {:<12}  A={:02X} F={:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X}",
+            self.pc,
+            bytes,
+            text,
+            self.acc,
+            self.flag.value(),
+            self.registers[Register::B as usize],
+            self.registers[Register::C as usize],
+            self.registers[Register::D as usize],
+            self.registers[Register::E as usize],
+            self.registers[Register::H as usize],
+            self.registers[Register::L as usize],
+            self.sp,
+        );
+        if let Some(w) = self.trace.0.as_mut() {
+            // tracing is a debugging aid, so a failing writer must not stop the CPU
+            let _ = writeln!(w, "{}", line);
+        }
+    }
+
+    /// Starts counting how often each opcode byte is executed.
+    pub fn enable_opcode_profiling(&mut self) {
+        self.profile.get_or_insert_with(|| Box::new([0; 256]));
+    }
+
+    /// Executions per opcode byte since profiling was enabled; all zero if it is not.
+    pub fn opcode_profile(&self) -> &[u64; 256] {
+        static EMPTY: [u64; 256] = [0; 256];
+        self.profile.as_deref().unwrap_or(&EMPTY)
+    }
+
+    /// One `MNEMONIC count` line per executed mnemonic, most frequent first.
+    pub fn profile_report(&self) -> String {
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        for (n, &count) in self.opcode_profile().iter().enumerate() {
+            if count > 0 {
+                *counts.entry(Opcode::from(n as u8).mnemonic()).or_default() += count;
+            }
+        }
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+            .iter()
+            .map(|(mnemonic, count)| format!("{:<5} {}\n", mnemonic, count))
+            .collect()
+    }
+
+    /// Connects `device` to every `IN` and `OUT` port. Without a device, `IN`
+    /// reads the open-bus value and `OUT` is ignored.
+    pub fn set_device(&mut self, device: Box<dyn Device>) {
+        self.device.0 = Some(device);
+    }
+
+    /// What `IN` reads from a port no device answers on, 0xFF by default.
+    pub fn set_open_bus(&mut self, value: u8) {
+        self.open_bus = value;
+    }
+
+    /// Queues `bytes` for `IN port`, which reads them in order before asking
+    /// the device again. With no device on the port, it reads the open-bus
+    /// value once they run out.
+    pub fn queue_input(&mut self, port: u8, bytes: &[u8]) {
+        self.input_queue.entry(port).or_default().extend(bytes);
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints
+            .0
+            .get_or_insert_with(HashMap::new)
+            .insert(addr, None);
+    }
+
+    /// Adds a breakpoint at `addr` that only stops when `cond` returns true.
+    pub fn add_conditional_breakpoint(&mut self, addr: u16, cond: Condition) {
+        self.breakpoints
+            .0
+            .get_or_insert_with(HashMap::new)
+            .insert(addr, Some(cond));
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        if let Some(breakpoints) = self.breakpoints.0.as_mut() {
+            breakpoints.remove(&addr);
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        if let Some(trap) = self.opcode_trap.0.as_ref() {
+            if trap(Opcode::from(self.get_value(self.pc()))) {
+                return true;
+            }
+        }
+        let breakpoint = self
+            .breakpoints
+            .0
+            .as_ref()
+            .and_then(|breakpoints| breakpoints.get(&self.pc));
+        match breakpoint {
+            Some(Some(cond)) => cond(self),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Runs `f` whenever a `CALL`, a taken conditional call or an `RST`
+    /// reaches `addr`, e.g. to implement a ROM entry point in Rust.
+    ///
+    /// `f` runs after the return address has been pushed and PC set to
+    /// `addr`. If it leaves PC there, the CPU then returns as if the
+    /// subroutine had executed `RET`, without counting its cycles; otherwise
+    /// execution carries on wherever `f` pointed PC.
+    pub fn set_call_hook(&mut self, addr: u16, f: CallHook) {
+        self.call_hooks
+            .0
+            .get_or_insert_with(HashMap::new)
+            .insert(addr, f);
+    }
+
+    pub fn remove_call_hook(&mut self, addr: u16) {
+        if let Some(hooks) = self.call_hooks.0.as_mut() {
+            hooks.remove(&addr);
+        }
+    }
+
+    /// Calls `f` before each instruction `run_once` executes, like a trace
+    /// that can look at the whole CPU. Interrupts and halted steps are skipped.
+    pub fn set_step_hook(&mut self, f: StepHook) {
+        self.step_hook.0 = Some(f);
+    }
+
+    pub fn clear_step_hook(&mut self) {
+        self.step_hook.0 = None;
+    }
+
+    /// Makes `run_until_breakpoint` also stop in front of any instruction
+    /// whose opcode `predicate` accepts, e.g. every `OUT`.
+    pub fn set_opcode_trap(&mut self, predicate: OpcodeTrap) {
+        self.opcode_trap.0 = Some(predicate);
+    }
+
+    pub fn clear_opcode_trap(&mut self) {
+        self.opcode_trap.0 = None;
+    }
+
+    /// Runs until the next instruction is at a breakpoint whose condition holds,
+    /// or is caught by the opcode trap, returning its address, or until the CPU
+    /// halts with no interrupt ready to wake it, returning `None`.
+    ///
+    /// The instruction at the starting PC always runs, even when a breakpoint
+    /// or the opcode trap would stop on it: breakpoints are only checked from
+    /// the second instruction on. So calling this again after it stopped steps
+    /// past that breakpoint rather than stopping on it straight away, and a
+    /// breakpoint set at PC before the first call is not hit until PC comes
+    /// back to it. When the CPU starts halted with an interrupt ready, that
+    /// first step takes the interrupt.
+    pub fn run_until_breakpoint(&mut self) -> Option<u16> {
+        if self.halted && !self.interrupt_ready() {
+            return None;
+        }
+        self.run_once();
+        loop {
+            if self.halted && !self.interrupt_ready() {
+                return None;
+            }
+            if self.at_breakpoint() {
+                return Some(self.pc);
+            }
+            self.run_once();
+        }
+    }
+
+    /// Executes the instruction at PC, or waits for an interrupt while halted.
+    ///
+    /// Undocumented opcodes run as the instruction they alias, and a PC past
+    /// the end of memory panics. `run_once_checked` reports both as errors.
+    #[inline]
+    pub fn run_once(&mut self) {
+        if self.events.0.is_some() {
+            self.fire_due_events();
+        }
+        let interrupt = self.interrupt_ready();
+        if self.halted && !interrupt {
+            return;
+        }
+        self.record_step();
+        if interrupt {
+            self.handle_interrupt();
+            return;
+        }
+        if self.trace.0.is_some() {
+            self.write_trace();
+        }
+        if let Some(mut hook) = self.step_hook.0.take() {
+            hook(self);
+            self.step_hook.0 = Some(hook);
+        }
+        let n = self.data[self.pc()];
+        if let Some(profile) = self.profile.as_mut() {
+            profile[n as usize] += 1;
+        }
+        DISPATCH[usize::from(n)](self);
+        self.instructions += 1;
+    }
+
+    /// Runs `event` before the first instruction that starts at or after
+    /// `at_cycle`, e.g. a video or timer interrupt calling `request_interrupt`.
+    /// Events due at the same cycle run in the order they were scheduled.
+    pub fn schedule(&mut self, at_cycle: u64, event: Event) {
+        let events = self.events.0.get_or_insert_with(VecDeque::new);
+        let i = events.partition_point(|&(at, _)| at <= at_cycle);
+        events.insert(i, (at_cycle, event));
+    }
+
+    fn next_event_cycle(&self) -> Option<u64> {
+        self.events.0.as_ref()?.front().map(|&(at, _)| at)
+    }
+
+    fn fire_due_events(&mut self) {
+        loop {
+            let now = self.cycles;
+            let event = match self.events.0.as_mut() {
+                Some(events) if events.front().is_some_and(|&(at, _)| at <= now) => {
+                    events.pop_front()
+                }
+                _ => None,
+            };
+            match event {
+                // an event may schedule another one
+                Some((_, event)) => event(self),
+                None => return,
+            }
+        }
+    }
+
+    /// Adds the wait states `f` returns for each memory access to the cycle
+    /// count, e.g. to model slow memory or DRAM refresh on a particular board.
+    /// `f` sees the same accesses as `enable_mem_trace`.
+    pub fn set_wait_state_hook(&mut self, f: WaitStateHook) {
+        self.wait_state_hook.0 = Some(f);
+    }
+
+    /// Makes `run_once_checked` call `f` with the byte at PC instead of failing
+    /// on an undocumented opcode. `f` must advance PC or halt the CPU itself.
+    pub fn set_illegal_opcode_handler(&mut self, f: IllegalOpcodeHandler) {
+        self.illegal_opcode_handler.0 = Some(f);
+    }
+
+    /// Like `run_once`, but returns the opcode it executed (`HLT` while halted),
+    /// or an error instead of running an undocumented opcode or fetching past
+    /// the end of memory. Memory accessed by the instruction itself is not checked.
+    ///
+    /// An undocumented opcode goes to the handler from `set_illegal_opcode_handler`
+    /// if there is one, and the opcode the byte aliases is returned.
+    ///
+    /// With `enable_uninitialized_read_detection`, an instruction that reads an
+    /// uninitialized cell still runs, and then `UninitializedRead` reports the
+    /// first such address.
+    #[must_use = "an illegal opcode is only reported through the returned error"]
+    pub fn run_once_checked(&mut self) -> Result<Opcode> {
+        // an event may raise the interrupt this step has to report
+        if self.events.0.is_some() {
+            self.fire_due_events();
+        }
+        if self.interrupt_ready() {
+            let opcode = self.interrupt_opcode();
+            self.record_step();
+            self.handle_interrupt();
+            return Ok(opcode);
+        }
+        if self.halted {
+            return Ok(Opcode::HLT);
+        }
+        let n = *self.data.get(self.pc()).ok_or(Error::OutOfBounds)?;
+        let opcode = match Opcode::decode(n) {
+            Ok(opcode) => opcode,
+            Err(e) => {
+                let mut handler = match self.illegal_opcode_handler.0.take() {
+                    Some(handler) => handler,
+                    None => return Err(e),
+                };
+                handler(self, n);
+                // the handler may have installed a replacement for itself
+                self.illegal_opcode_handler.0.get_or_insert(handler);
+                return Ok(Opcode::from(n));
+            }
+        };
+        if !self.operands_fit(opcode) {
+            return Err(Error::OutOfBounds);
+        }
+        self.uninitialized_read = None;
+        self.run_once();
+        match self.uninitialized_read.take() {
+            Some(addr) => Err(Error::UninitializedRead { addr }),
+            None => Ok(opcode),
+        }
+    }
+
+    /// Runs one instruction with `run_once_checked` and says whether the CPU
+    /// can go on, so a driver loop can be `while cpu.tick() == Tick::Running {}`.
+    pub fn tick(&mut self) -> Tick {
+        match self.run_once_checked() {
+            Ok(_) if self.halted && !self.interrupt_ready() => Tick::Halted,
+            Ok(_) => Tick::Running,
+            Err(Error::IllegalValue) => Tick::IllegalOpcode(self.data[self.pc()]),
+            Err(Error::UninitializedRead { addr }) => Tick::UninitializedRead(addr),
+            Err(_) => Tick::OutOfBounds,
+        }
+    }
+
+    /// Decodes and disassembles the instruction at PC without executing it.
+    /// Fails like `run_once_checked` would.
+    pub fn peek(&self) -> Result<(Opcode, String)> {
+        let n = *self.data.get(self.pc()).ok_or(Error::OutOfBounds)?;
+        let opcode = Opcode::decode(n)?;
+        if !self.operands_fit(opcode) {
+            return Err(Error::OutOfBounds);
+        }
+        Ok((opcode, disassemble(&self.instruction_bytes(), 0).0))
+    }
+
+    /// The 8-bit operand of the instruction at PC, e.g. 0x12 for `MVI A,$12`,
+    /// or `None` if it has no 8-bit operand.
+    pub fn current_immediate8(&self) -> Option<u8> {
+        let opcode = Opcode::from(*self.data.get(self.pc())?);
+        if opcode.length() == 2 {
+            Some(self.operand_u8())
+        } else {
+            None
+        }
+    }
+
+    /// The 16-bit operand of the instruction at PC, e.g. 0x0100 for
+    /// `JMP $0100`, or `None` if it has no 16-bit operand.
+    pub fn current_immediate16(&self) -> Option<u16> {
+        let opcode = Opcode::from(*self.data.get(self.pc())?);
+        if opcode.length() == 3 {
+            Some(self.operand_u16())
+        } else {
+            None
+        }
+    }
+
+    /// Runs `n` steps with `step_debug`, reporting for each one which
+    /// registers, flags and PC it changed.
+    pub fn step_n_with_deltas(&mut self, n: usize) -> Vec<StepDelta> {
+        let regs = |cpu: &CPU| {
+            let mut regs = [0; 7];
+            regs[..6].copy_from_slice(&cpu.registers);
+            regs[6] = cpu.acc;
+            regs
+        };
+        (0..n)
+            .map(|_| {
+                let (before, flag) = (regs(self), self.flag);
+                let step = self.step_debug();
+                let after = regs(self);
+                let registers = (0..7)
+                    .filter(|&i| before[i] != after[i])
+                    .map(|i| {
+                        let reg = if i == 6 {
+                            Register::Acc
+                        } else {
+                            Register::from(i)
+                        };
+                        (reg, before[i], after[i])
+                    })
+                    .collect();
+                StepDelta {
+                    step,
+                    registers,
+                    flags: Some((flag, self.flag)).filter(|(old, new)| old != new),
+                    pc: (step.pc, self.pc),
+                }
+            })
+            .collect()
+    }
+
+    /// What the next `run_once` would do, worked out on a scratch copy: the
+    /// state afterwards and the memory writes it makes, in order.
+    ///
+    /// The copy is a `clone`, so it holds all of memory and none of the hooks,
+    /// and the preview can differ from the real step wherever a hook would
+    /// have stepped in:
+    ///
+    /// - no device: `IN` reads queued input or the open-bus value and `OUT`
+    ///   goes nowhere;
+    /// - no call hooks: a hooked `CALL` or `RST` just jumps to the address;
+    /// - no step hook, wait-state hook or trace, so none of them is called
+    ///   or written to;
+    /// - no scheduled events, so none fire;
+    /// - no illegal-opcode handler, opcode trap or breakpoints, which
+    ///   `run_once` does not use anyway.
+    pub fn preview_step(&self) -> (CpuState, Vec<(u16, u8)>) {
+        let mut scratch = self.clone();
+        scratch.rewind = None;
+        scratch.mem_trace = Some(Vec::new());
+        scratch.run_once();
+        let writes = scratch
+            .take_mem_trace()
+            .into_iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .map(|access| (access.addr, access.value))
+            .collect();
+        (scratch.state(), writes)
+    }
+
+    /// Both possible next PCs of the instruction at PC, without executing it.
+    pub fn successors(&self) -> Successors {
+        let opcode = Opcode::from(self.get_value(self.pc()));
+        let branch_target = match opcode.mnemonic() {
+            _ if opcode.class() != OpClass::Branch => None,
+            "RST" => Some(u16::from(u8::from(opcode) & 0b0011_1000)),
+            "PCHL" => Some(self.memory_address() as u16),
+            m if m.starts_with('R') => Some(self.word_at(self.sp)),
+            _ => Some(self.operand_u16()),
+        };
+        Successors {
+            fall_through: self.pc.wrapping_add(opcode.length() as u16),
+            branch_target,
+        }
+    }
+
+    /// Like `run_once`, but reports what was executed.
+    pub fn step_debug(&mut self) -> StepRecord {
+        if self.events.0.is_some() {
+            self.fire_due_events();
+        }
+        let pc = self.pc;
+        let (opcode, branch_taken) = if self.interrupt_ready() {
+            (self.interrupt_opcode(), None)
+        } else if self.halted {
+            (Opcode::HLT, None)
+        } else {
+            let opcode = Opcode::from(self.data[self.pc()]);
+            (opcode, self.branch_taken(opcode))
+        };
+        let cycles = self.cycles;
+        self.run_once();
+        StepRecord {
+            pc,
+            opcode,
+            cycles: self.cycles - cycles,
+            branch_taken,
+            total_cycles: self.cycles,
+            total_instructions: self.instructions,
+        }
+    }
+
+    /// Steps with `step_debug`, yielding each record, until the CPU is halted
+    /// and no interrupt can wake it.
+    pub fn trace_iter(&mut self) -> impl Iterator<Item = StepRecord> + '_ {
+        std::iter::from_fn(move || {
+            if self.halted && !self.interrupt_ready() {
+                None
+            } else {
+                Some(self.step_debug())
+            }
+        })
+    }
+
+    /// Takes the latched interrupt request if interrupts are enabled: wakes
+    /// the CPU, disables interrupts and calls the interrupt address. This
+    /// costs as many T-states as the instruction it amounts to, 11 for an `RST`.
+    pub fn handle_interrupt(&mut self) {
+        if self.interrupt_ready() {
+            self.cycles += u64::from(self.interrupt_opcode().cycles().0);
+            self.interrupts_serviced += 1;
+            self.pending_interrupt = false;
+            self.interrupted = false;
+            self.halted = false;
+            self.stack_push(self.pc);
+            self.pc = self.interrupted_addr;
+        }
+    }
+
+    /// Requests an interrupt that calls `addr`. The request stays latched
+    /// while interrupts are disabled and is taken before the next instruction
+    /// once they are enabled.
+    #[inline]
+    pub fn send_interrupt(&mut self, addr: u16) {
+        self.interrupted_addr = addr;
+        self.pending_interrupt = true;
+    }
+
+    /// Requests an interrupt that executes `RST rst`, like a device putting
+    /// that instruction on the bus. It jumps to `rst * 8` past the interrupt
+    /// vector base.
+    pub fn request_interrupt(&mut self, rst: u8) {
+        let vector = u16::from(rst & 0b111) << 3;
+        self.send_interrupt(self.interrupt_vector_base.wrapping_add(vector));
+    }
+
+    /// Takes an interrupt right away with `opcode` as the instruction the
+    /// device puts on the bus, if interrupts are enabled; otherwise does
+    /// nothing. Like `handle_interrupt`, this wakes the CPU and disables
+    /// interrupts. PC is not advanced past the injected byte, so `RST n` (0xC7
+    /// | n << 3) pushes the address of the interrupted instruction.
+    ///
+    /// Fails with `IllegalValue` unless `opcode` is a documented one-byte
+    /// instruction; use `send_interrupt` for a `CALL`.
+    pub fn inject_instruction(&mut self, opcode: u8) -> Result<()> {
+        let opcode = Opcode::decode(opcode)?;
+        if opcode.length() != 1 {
+            return Err(Error::IllegalValue);
+        }
+        if !self.interrupted {
+            return Ok(());
+        }
+        self.record_step();
+        self.interrupts_serviced += 1;
+        self.interrupted = false;
+        self.halted = false;
+        // dispatch steps over the byte as if it had been fetched from memory
+        self.pc = self.pc.wrapping_sub(1);
+        self.execute_injected(opcode);
+        Ok(())
+    }
+
+    /// Moves the restart vectors used by `request_interrupt` from 0 to `base`,
+    /// for boards that route interrupts through a jump table elsewhere.
+    pub fn set_interrupt_vector_base(&mut self, base: u16) {
+        self.interrupt_vector_base = base;
+    }
+
+    /// Whether an interrupt request is latched and not yet taken.
+    #[inline]
+    pub fn has_pending_interrupt(&self) -> bool {
+        self.pending_interrupt
+    }
+
+    #[inline]
+    fn interrupt_ready(&self) -> bool {
+        self.pending_interrupt && self.interrupted
+    }
+
+    /// The instruction a taken interrupt amounts to: `RST n` for the eight
+    /// restart vectors, otherwise a `CALL` of the interrupt address.
+    fn interrupt_opcode(&self) -> Opcode {
+        let vector = self
+            .interrupted_addr
+            .wrapping_sub(self.interrupt_vector_base);
+        if vector & !0b0011_1000 == 0 {
+            Opcode::from(0b1100_0111 | vector as u8)
+        } else {
+            Opcode::CALL
+        }
+    }
+
+    /// Runs `hook` after every instruction until it returns `Break`, or until
+    /// the CPU is halted and no interrupt can wake it.
+    pub fn run_with(&mut self, mut hook: impl FnMut(&mut CPU) -> ControlFlow<()>) {
+        while !self.halted || self.interrupt_ready() {
+            self.run_once();
+            if hook(self).is_break() {
+                return;
+            }
+        }
+    }
+
+    /// Runs one frame of `cycles_per_frame` T-states and returns how far the
+    /// last instruction ran past its end, to be taken off the next frame.
+    ///
+    /// With `mid_frame_interrupt` of `Some((at, rst))`, `request_interrupt(rst)`
+    /// is called once `at` T-states of the frame have run, e.g. for the
+    /// mid-screen interrupt of Space Invaders. A halted CPU idles through the
+    /// rest of the frame, or until that interrupt or a scheduled event.
+    pub fn run_frame(
+        &mut self,
+        cycles_per_frame: u64,
+        mid_frame_interrupt: Option<(u64, u8)>,
+    ) -> u64 {
+        let start = self.cycles;
+        let end = start + cycles_per_frame;
+        let mut pending = mid_frame_interrupt;
+        while self.cycles < end {
+            if let Some((at, rst)) = pending {
+                if self.cycles >= start + at {
+                    self.request_interrupt(rst);
+                    pending = None;
+                }
+            }
+            let event_due = self.next_event_cycle().is_some_and(|at| at <= self.cycles);
+            if self.halted && !self.interrupt_ready() && !event_due {
+                let mut wake = end;
+                if let Some((at, _)) = pending {
+                    wake = wake.min(start + at);
+                }
+                if let Some(at) = self.next_event_cycle() {
+                    wake = wake.min(at);
+                }
+                self.cycles = wake;
+                continue;
+            }
+            self.run_once();
+        }
+        self.cycles - end
+    }
+
+    /// Runs until the CPU is halted and no interrupt can wake it, so `HLT`
+    /// after `DI` stops for good.
+    pub fn run_until_halt(&mut self) {
+        while !self.halted || self.interrupt_ready() {
+            self.run_once();
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case, clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_NOP() {
+        let data = vec![Opcode::NOP.into()];
+        let mut cpu = CPU::new(data);
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+    }
+
+    #[test]
+    fn test_LXI() {
+        let data = vec![
+            Opcode::LXI_H.into(),
+            1,
+            3,
+            Opcode::LXI_SP.into(),
+            0x34,
+            0x12,
+        ];
+        let mut cpu = CPU::new(data);
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.registers[Register::H as usize], 3);
+        assert_eq!(cpu.registers[Register::L as usize], 1);
+
+        cpu.run_once();
+        assert_eq!(cpu.sp(), 0x1234);
+    }
+
+    #[test]
+    fn test_read_write_u16() {
+        let mut cpu = CPU::new(vec![0; 4]);
+
+        cpu.write_u16(1, 0xBEEF);
+        assert_eq!(cpu.data, vec![0, 0xEF, 0xBE, 0]);
+        assert_eq!(cpu.read_u16(1), 0xBEEF);
+        assert_eq!(cpu.try_read_u16(2).unwrap(), 0x00BE);
+        assert!(matches!(cpu.try_read_u16(3), Err(Error::OutOfBounds)));
+        assert!(matches!(cpu.try_write_u16(3, 0), Err(Error::OutOfBounds)));
+        cpu.try_write_u16(0, 0x1234).unwrap();
+        assert_eq!(cpu.read_u16(0), 0x1234);
+    }
+
+    #[test]
+    fn test_STAX() {
+        let data = vec![Opcode::STAX_B.into(), Opcode::STAX_D.into(), 0, 0];
+
+        let mut cpu = CPU::new(data);
+        cpu.registers[Register::B as usize] = 0;
+        cpu.registers[Register::C as usize] = 2;
+        cpu.acc = 1;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.data[2], 1);
+
+        cpu.registers[Register::D as usize] = 0;
+        cpu.registers[Register::E as usize] = 3;
+        cpu.acc = 255;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.data[3], 255);
+    }
+
+    #[test]
+    fn test_STA() {
+        let data = vec![Opcode::STA.into(), 3, 0, 0];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 255;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.data[3], 255);
+    }
+
+    #[test]
+    fn test_LDAX() {
+        let data = vec![Opcode::LDAX_B.into(), Opcode::LDAX_D.into(), 1, 255];
+
+        let mut cpu = CPU::new(data);
+        cpu.registers[Register::B as usize] = 0;
+        cpu.registers[Register::C as usize] = 2;
+        cpu.acc = 0;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 1);
+
+        cpu.registers[Register::D as usize] = 0;
+        cpu.registers[Register::E as usize] = 3;
+        cpu.acc = 0;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 255);
+    }
+
+    #[test]
+    fn test_LDA() {
+        let data = vec![Opcode::LDA.into(), 3, 0, 255];
+
+        let mut cpu = CPU::new(data);
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.acc, 255);
+    }
+
+    #[test]
+    fn test_INX() {
+        let data = vec![Opcode::INX_D.into(), Opcode::INX_SP.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::D as usize] = 0x38;
+        cpu.registers[Register::E as usize] = 0xff;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.registers[Register::D as usize], 0x39);
+        assert_eq!(cpu.registers[Register::E as usize], 0x00);
+
+        let sp = cpu.sp();
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(sp + 1, cpu.sp());
+    }
+
+    #[test]
+    fn test_DCX() {
+        let data = vec![Opcode::DCX_H.into(), Opcode::DCX_SP.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::H as usize] = 0x98;
+        cpu.registers[Register::L as usize] = 0x00;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.registers[Register::H as usize], 0x97);
+        assert_eq!(cpu.registers[Register::L as usize], 0xff);
+
+        let sp = cpu.sp();
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(sp - 1, cpu.sp());
+    }
+
+    #[test]
+    fn test_INX_DCX_wrap() {
+        let data = vec![
+            Opcode::INX_B.into(),
+            Opcode::DCX_B.into(),
+            Opcode::DCX_SP.into(),
+            Opcode::INX_SP.into(),
+        ];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::B as usize] = 0xFF;
+        cpu.registers[Register::C as usize] = 0xFF;
+        cpu.run_once();
+        assert_eq!(cpu.bc_addr(), 0x0000);
+        cpu.run_once();
+        assert_eq!(cpu.bc_addr(), 0xFFFF);
+
+        cpu.sp = 0;
+        cpu.run_once();
+        assert_eq!(cpu.sp(), 0xFFFF);
+        cpu.run_once();
+        assert_eq!(cpu.sp(), 0);
+    }
+
+    #[test]
+    fn test_INR() {
+        let data = vec![Opcode::INR_C.into()];
+        let mut cpu = CPU::new(data);
+        cpu.registers[Register::C as usize] = 0x99;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.registers[Register::C as usize], 0x9A);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), true);
+    }
+
+    #[test]
+    fn test_DCR() {
+        let data = vec![Opcode::DCR_M.into(), 0x40];
+        let mut cpu = CPU::new(data);
+        cpu.set_memory_address(1);
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.data[1], 0x3f);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), false);
+    }
+
+    #[test]
+    fn test_MVI() {
+        let data = vec![Opcode::MVI_A.into(), 1, Opcode::MVI_M.into(), 255];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 1);
+
+        cpu.set_memory_address(1);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 4);
+        assert_eq!(cpu.data[1], 255);
+    }
+
+    #[test]
+    fn test_RLC() {
+        let data = vec![Opcode::RLC.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0xf2;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 0xe5);
+        assert_eq!(cpu.flag.carry_flag(), true);
+    }
+
+    #[test]
+    fn test_RRC() {
+        let data = vec![Opcode::RRC.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0xf2;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 0x79);
+        assert_eq!(cpu.flag.carry_flag(), false);
+    }
+
+    #[test]
+    fn test_rotate_carry() {
+        let data = vec![Opcode::RRC.into(), Opcode::RAL.into(), Opcode::RAL.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x01;
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x80);
+        assert_eq!(cpu.flag.carry_flag(), true);
+
+        cpu.acc = 0x40;
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x81);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x02);
+        assert_eq!(cpu.flag.carry_flag(), true);
+    }
+
+    #[test]
+    fn test_RAL() {
+        let data = vec![Opcode::RAL.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0xb5;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 0x6a);
+        assert_eq!(cpu.flag.carry_flag(), true);
+    }
+
+    #[test]
+    fn test_RAR() {
+        let data = vec![Opcode::RAR.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x6a;
+        cpu.flag.set_carry_flag(true);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 0xb5);
+        assert_eq!(cpu.flag.carry_flag(), false);
+    }
+
+    #[test]
+    fn test_SHLD() {
+        let data = vec![Opcode::SHLD.into(), 3, 0, 0, 0];
+        let mut cpu = CPU::new(data);
+        cpu.registers[Register::L as usize] = 1;
+        cpu.registers[Register::H as usize] = 2;
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.data[3], 1);
+        assert_eq!(cpu.data[4], 2);
+    }
+
+    #[test]
+    fn test_LHLD() {
+        let data = vec![Opcode::LHLD.into(), 3, 0, 1, 2];
+        let mut cpu = CPU::new(data);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.registers[Register::L as usize], 1);
+        assert_eq!(cpu.registers[Register::H as usize], 2);
+    }
+
+    #[test]
+    fn test_DAA() {
+        let data = vec![Opcode::DAA.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x9b;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 1);
+        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+    }
+
+    #[test]
+    fn test_DAD() {
+        // sp == 5
+        let data = vec![Opcode::DAD_B.into(), Opcode::DAD_SP.into(), 0, 0, 0];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::B as usize] = 0x33;
+        cpu.registers[Register::C as usize] = 0x9f;
+        cpu.registers[Register::H as usize] = 0xa1;
+        cpu.registers[Register::L as usize] = 0x7b;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.registers[Register::H as usize], 0xd5);
+        assert_eq!(cpu.registers[Register::L as usize], 0x1a);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.registers[Register::H as usize], 0xd5);
+        assert_eq!(cpu.registers[Register::L as usize], 0x1f);
+    }
+
+    #[test]
+    fn test_STC() {
+        let data = vec![Opcode::STC.into()];
+        let mut cpu = CPU::new(data);
+
+        assert_eq!(cpu.flag.carry_flag(), false);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.carry_flag(), true);
+    }
+
+    #[test]
+    fn test_CMA() {
+        let data = vec![Opcode::CMA.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x51;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 0xae);
+    }
+
+    #[test]
+    fn test_CMC() {
+        let data = vec![Opcode::CMC.into(), Opcode::CMC.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.carry_flag(), true);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.flag.carry_flag(), false);
+    }
+
+    #[test]
+    fn test_MOV() {
+        let data = vec![
+            Opcode::MOV_AE.into(),
+            Opcode::MOV_DD.into(),
+            Opcode::MOV_MA.into(),
+            0,
+        ];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::E as usize] = 1;
+        cpu.registers[Register::D as usize] = 2;
+        cpu.set_memory_address(3);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 1);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.registers[Register::D as usize], 2);
+
+        cpu.acc = 3;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.data[3], 3);
+    }
+
+    #[test]
+    fn test_HLT() {
+        let data = vec![Opcode::HLT.into()];
+        let mut cpu = CPU::new(data);
+
+        assert_eq!(cpu.halted, false);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.halted, true);
+    }
+
+    #[test]
+    fn test_ADD() {
+        let data = vec![Opcode::ADD_D.into(), Opcode::ADD_M.into(), 2];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::D as usize] = 0x2e;
+        cpu.acc = 0x6c;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.acc, 0x9a);
+
+        cpu.set_memory_address(2);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.acc, 0x9c);
+    }
+
+    #[test]
+    fn test_ADC() {
+        let data = vec![Opcode::ADC_C.into(), Opcode::ADC_C.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::C as usize] = 0x3d;
+        cpu.acc = 0x42;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(cpu.acc, 0x7f);
+
+        cpu.registers[Register::C as usize] = 0x3d;
+        cpu.acc = 0x42;
+        cpu.flag.set_carry_flag(true);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+        assert_eq!(cpu.acc, 0x80);
+    }
+
+    #[test]
+    fn test_SUB() {
+        let data = vec![Opcode::SUB_B.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x3e;
+        cpu.registers[Register::B as usize] = 0x3e;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.acc, 0);
+        assert_eq!(cpu.flag.zero_flag(), true);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+    }
+
+    #[test]
+    fn test_SBB() {
+        let data = vec![Opcode::SBB_L.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::L as usize] = 2;
+        cpu.acc = 4;
+        cpu.flag.set_carry_flag(true);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+        assert_eq!(cpu.acc, 1);
+    }
+
+    #[test]
+    fn test_ANA() {
+        let data = vec![Opcode::ANA_C.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::C as usize] = 0x0f;
+        cpu.acc = 0xfc;
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.acc, 0x0c);
+    }
+
+    #[test]
+    fn test_XRA() {
+        let data = vec![Opcode::XRA_B.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::B as usize] = 0b0101_1100;
+        cpu.acc = 0b0111_1000;
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        //        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(cpu.acc, 0b0010_0100);
+    }
+
+    #[test]
+    fn test_ORA() {
+        let data = vec![Opcode::ORA_C.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::C as usize] = 0x0f;
+        cpu.acc = 0x33;
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(cpu.acc, 0x3f);
+    }
+
+    #[test]
+    fn test_CMP() {
+        let data = vec![Opcode::CMP_E.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::E as usize] = 0x5;
+        cpu.acc = 0xa;
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+        assert_eq!(cpu.acc, 0xa);
+    }
+
+    #[test]
+    fn test_RET() {
+        let data = vec![Opcode::RET.into(), 0, 0, Opcode::NOP.into(), 0, 0];
+        let mut cpu = CPU::new(data);
+
+        // return to nop
+        cpu.stack_push(3);
+        assert_eq!(cpu.data[cpu.data.len() - 2], 3);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+    }
+
+    #[test]
+    fn test_CALL() {
+        let data = vec![
+            Opcode::CALL.into(),
+            4,
+            0,
+            Opcode::NOP.into(),
+            Opcode::NOP.into(),
+            Opcode::RET.into(),
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut cpu = CPU::new(data);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 4);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 5);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 4);
+    }
+
+    #[test]
+    fn test_PUSH() {
+        let data = vec![
+            Opcode::PUSH_D.into(),
+            Opcode::PUSH_PSW.into(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::D as usize] = 0x8f;
+        cpu.registers[Register::E as usize] = 0x9d;
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.data[cpu.data.len() - 1], 0x8f);
+        assert_eq!(cpu.data[cpu.data.len() - 2], 0x9d);
+
+        cpu.acc = 0x1f;
+        cpu.flag.set_carry_flag(true);
+        cpu.flag.set_zero_flag(true);
+        cpu.flag.set_parity_flag(true);
+        cpu.flag.set_sign_flag(false);
+        cpu.flag.set_auxiliary_carry_flag(false);
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.data[cpu.data.len() - 3], 0x1f);
+        assert_eq!(cpu.data[cpu.data.len() - 4], 0x47);
+    }
+
+    #[test]
+    fn test_POP() {
+        let data = vec![
+            Opcode::POP_H.into(),
+            Opcode::PUSH_PSW.into(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut cpu = CPU::new(data);
+
+        cpu.stack_push(0x933d);
+        cpu.run_once();
+
+        assert_eq!(cpu.pc(), 1);
+        assert_eq!(cpu.registers[Register::H as usize], 0x93);
+        assert_eq!(cpu.registers[Register::L as usize], 0x3d);
+    }
+
+    #[test]
+    fn test_JMP() {
+        // loop
+        let data = vec![Opcode::JMP.into(), 3, 0, Opcode::JMP.into(), 0, 0];
+        let mut cpu = CPU::new(data);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 0);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+    }
+
+    #[test]
+    fn test_ADI() {
+        let data = vec![Opcode::ADI.into(), 0x42];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x14;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0x56);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), false);
+    }
+
+    #[test]
+    fn test_SUI() {
+        let data = vec![Opcode::SUI.into(), 1];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0xFF);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+    }
+
+    #[test]
+    fn test_ANI() {
+        let data = vec![Opcode::ANI.into(), 0x0f];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x3a;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0x0a);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.zero_flag(), false);
+    }
+
+    #[test]
+    fn test_ORI() {
+        let data = vec![Opcode::ORI.into(), 0x0f];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0xb5;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0xbf);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.zero_flag(), false);
+    }
+
+    #[test]
+    fn test_ACI() {
+        let data = vec![Opcode::ACI.into(), 0x42];
+        let mut cpu = CPU::new(data);
+
+        cpu.flag.set_carry_flag(true);
+        cpu.acc = 0x14;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0x57);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+    }
+
+    #[test]
+    fn test_SBI() {
+        let data = vec![Opcode::SBI.into(), 0x1];
+        let mut cpu = CPU::new(data);
+
+        cpu.flag.set_carry_flag(true);
+        cpu.acc = 0;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0xFE);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        // 0x00 + 0xFE (the complement of 1 + borrow) carries nothing out of bit 3
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+
+        let mut cpu = CPU::new(vec![Opcode::SBI.into(), 0x02]);
+        cpu.flag.set_carry_flag(true);
+        cpu.acc = 0x05;
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x02);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+    }
+
+    #[test]
+    fn test_SUB_aux_carry() {
+        // AC comes from the low nibble, not the whole byte
+        let mut cpu = CPU::new(vec![Opcode::SUB_B.into(), Opcode::SUB_B.into()]);
+        cpu.acc = 0x10;
+        cpu.registers[Register::B as usize] = 0x01;
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x0F);
+        assert_eq!(cpu.flag.auxiliary_flag(), false);
+
+        cpu.registers[Register::B as usize] = 0x10;
+        cpu.acc = 0x01;
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0xF1);
+        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+    }
+
+    #[test]
+    fn test_SBB_borrow_past_0xFF() {
+        let mut cpu = CPU::new(vec![Opcode::SBB_B.into()]);
+        cpu.acc = 0x00;
+        cpu.registers[Register::B as usize] = 0xFF;
+        cpu.flag.set_carry_flag(true);
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x00);
+        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.flag.zero_flag(), true);
+    }
+
+    #[test]
+    fn test_logical_immediate_aux_carry() {
+        // ANI sets AC to the OR of bit 3 of its operands, like ANA
+        let mut cpu = CPU::new(vec![Opcode::ANI.into(), 0x00]);
+        cpu.acc = 0x08;
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x00);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+
+        // XRI and ORI clear it, like XRA and ORA
+        for &opcode in [Opcode::XRI, Opcode::ORI].iter() {
+            let mut cpu = CPU::new(vec![opcode.into(), 0x01]);
+            cpu.flag.set_auxiliary_carry_flag(true);
+            cpu.run_once();
+            assert_eq!(cpu.acc, 0x01);
+            assert_eq!(cpu.flag.auxiliary_flag(), false);
+        }
+    }
+
+    #[test]
+    fn test_XRI() {
+        let data = vec![Opcode::XRI.into(), 0x81];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x3b;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0xBA);
+        assert_eq!(cpu.flag.parity_flag(), false);
+        assert_eq!(cpu.flag.sign_flag(), true);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.zero_flag(), false);
+    }
+
+    #[test]
+    fn test_CPI() {
+        let data = vec![Opcode::CPI.into(), 0x40];
+        let mut cpu = CPU::new(data);
+
+        cpu.acc = 0x4a;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 2);
+        assert_eq!(cpu.acc, 0x4a);
+        assert_eq!(cpu.flag.parity_flag(), true);
+        assert_eq!(cpu.flag.sign_flag(), false);
+        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.flag.zero_flag(), false);
+        assert_eq!(cpu.flag.auxiliary_flag(), true);
+    }
+
+    #[test]
+    fn test_compare_flags() {
+        // (acc, data, zero, carry, aux)
+        let cases = [
+            (0x42, 0x42, true, false, true),
+            (0x10, 0x01, false, false, false),
+            (0x21, 0x31, false, true, true),
+            (0x20, 0x2F, false, true, false),
+        ];
+        for &(acc, data, zero, carry, aux) in cases.iter() {
+            let code = vec![Opcode::CMP_B.into(), Opcode::CPI.into(), data];
+            let mut cpu = CPU::new(code);
+            cpu.acc = acc;
+            cpu.registers[Register::B as usize] = data;
+            for _ in 0..2 {
+                cpu.run_once();
+                assert_eq!(cpu.acc, acc);
+                assert_eq!(cpu.flag.zero_flag(), zero);
+                assert_eq!(cpu.flag.carry_flag(), carry);
+                assert_eq!(cpu.flag.auxiliary_flag(), aux);
+            }
+        }
+    }
+
+    #[test]
+    fn test_SPHL() {
+        let data = vec![Opcode::SPHL.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::H as usize] = 0x50;
+        cpu.registers[Register::L as usize] = 0x6c;
+        cpu.run_once();
+        assert_eq!(cpu.sp(), 0x506c);
+    }
+
+    #[test]
+    fn test_PCHL() {
+        let data = vec![Opcode::PCHL.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::H as usize] = 0x41;
+        cpu.registers[Register::L as usize] = 0x3e;
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 0x413e);
+    }
+
+    #[test]
+    fn test_XCHG() {
+        let data = vec![Opcode::XCHG.into()];
+        let mut cpu = CPU::new(data);
+
+        cpu.registers[Register::H as usize] = 0x00;
+        cpu.registers[Register::L as usize] = 0xFF;
+        cpu.registers[Register::D as usize] = 0x33;
+        cpu.registers[Register::E as usize] = 0x55;
+
+        cpu.run_once();
+
+        assert_eq!(cpu.registers[Register::H as usize], 0x33);
+        assert_eq!(cpu.registers[Register::L as usize], 0x55);
+        assert_eq!(cpu.registers[Register::D as usize], 0x00);
+        assert_eq!(cpu.registers[Register::E as usize], 0xFF);
+    }
+
+    #[test]
+    fn test_XTHL() {
+        let data = vec![Opcode::XTHL.into(), 0, 1, 2, 3];
+        let mut cpu = CPU::new(data);
+        cpu.stack_push(0x0DF0);
+
+        cpu.registers[Register::H as usize] = 0x0B;
+        cpu.registers[Register::L as usize] = 0x3C;
+        cpu.run_once();
+
+        assert_eq!(cpu.registers[Register::H as usize], 0x0D);
+        assert_eq!(cpu.registers[Register::L as usize], 0xF0);
+        assert_eq!(cpu.data[cpu.data.len() - 1], 0x0B);
+        assert_eq!(cpu.data[cpu.data.len() - 2], 0x3C);
     }
 
-    #[inline]
-    pub fn send_interrupt(&mut self, addr: u16) {
-        self.interrupted_addr = addr;
+    #[test]
+    fn test_exchange_stack_hl() {
+        let mut cpu = CPU::default();
+        cpu.set_pair(RegisterPair::HL, 0x1234);
+        cpu.set_value(0x1001, 0xCD);
+        cpu.set_value(0x1002, 0xAB);
+        cpu.sp = 0x1001;
+        cpu.exchange_stack_hl();
+        assert_eq!(cpu.pair(RegisterPair::HL), 0xABCD);
+        assert_eq!((cpu.get_value(0x1001), cpu.get_value(0x1002)), (0x34, 0x12));
+        assert_eq!(cpu.sp, 0x1001);
+        // and back again
+        cpu.exchange_stack_hl();
+        assert_eq!(cpu.pair(RegisterPair::HL), 0x1234);
+        assert_eq!((cpu.get_value(0x1001), cpu.get_value(0x1002)), (0xCD, 0xAB));
+
+        // L at the top of memory, H wrapped around to 0
+        cpu.set_value(0xFFFF, 0x78);
+        cpu.set_value(0x0000, 0x56);
+        cpu.sp = 0xFFFF;
+        cpu.exchange_stack_hl();
+        assert_eq!(cpu.pair(RegisterPair::HL), 0x5678);
+        assert_eq!((cpu.get_value(0xFFFF), cpu.get_value(0x0000)), (0x34, 0x12));
+        assert_eq!(cpu.sp, 0xFFFF);
     }
-}
-
-#[cfg(test)]
-#[allow(non_snake_case)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn test_NOP() {
-        let data = vec![Opcode::NOP.into()];
+    fn test_trace() {
+        let data = vec![Opcode::MVI_A.into(), 0x12, Opcode::JMP.into(), 0, 0];
         let mut cpu = CPU::new(data);
-        cpu.run_once();
+        let buf = SharedBuf::default();
 
-        assert_eq!(cpu.pc(), 1);
+        cpu.enable_trace(buf.clone());
+        cpu.run_once();
+        cpu.run_once();
+        assert!(buf.0.borrow().is_empty());
+
+        cpu.disable_trace().unwrap();
+        let out = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "0000  3E 12     MVI A,$12     A=00 F=02 BC=0000 DE=0000 HL=0000 SP=0005",
+                "0002  C3 00 00  JMP $0000     A=12 F=02 BC=0000 DE=0000 HL=0000 SP=0005",
+            ]
+        );
     }
 
     #[test]
-    fn test_LXI() {
-        let data = vec![Opcode::LXI_H.into(), 1, 3];
+    fn test_breakpoint() {
+        // MVI B,3; loop: DCR B; JNZ loop; HLT
+        let data = vec![
+            Opcode::MVI_B.into(),
+            3,
+            Opcode::DCR_B.into(),
+            Opcode::JNZ.into(),
+            2,
+            0,
+            Opcode::HLT.into(),
+        ];
         let mut cpu = CPU::new(data);
-        cpu.run_once();
 
-        assert_eq!(cpu.pc(), 3);
-        assert_eq!(cpu.registers[Register::H as usize], 1);
-        assert_eq!(cpu.registers[Register::L as usize], 3);
+        cpu.add_breakpoint(2);
+        assert_eq!(cpu.run_until_breakpoint(), Some(2));
+        assert_eq!(cpu.registers[Register::B as usize], 3);
+
+        cpu.remove_breakpoint(2);
+        assert_eq!(cpu.run_until_breakpoint(), None);
+        assert_eq!(cpu.is_halted(), true);
     }
 
     #[test]
-    fn test_STAX() {
-        let data = vec![Opcode::STAX_B.into(), Opcode::STAX_D.into(), 0, 0];
-
+    fn test_conditional_breakpoint() {
+        let data = vec![
+            Opcode::MVI_B.into(),
+            3,
+            Opcode::DCR_B.into(),
+            Opcode::JNZ.into(),
+            2,
+            0,
+            Opcode::HLT.into(),
+        ];
         let mut cpu = CPU::new(data);
-        cpu.registers[Register::B as usize] = 0;
-        cpu.registers[Register::C as usize] = 2;
-        cpu.acc = 1;
-        cpu.run_once();
 
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.data[2], 1);
+        cpu.add_conditional_breakpoint(3, Box::new(|cpu| cpu.registers[Register::B as usize] == 0));
+        assert_eq!(cpu.run_until_breakpoint(), Some(3));
+        assert_eq!(cpu.registers[Register::B as usize], 0);
+        assert_eq!(cpu.is_halted(), false);
+    }
 
-        cpu.registers[Register::D as usize] = 0;
-        cpu.registers[Register::E as usize] = 3;
-        cpu.acc = 255;
-        cpu.run_once();
+    #[test]
+    fn test_resume() {
+        let data = vec![Opcode::DI.into(), Opcode::HLT.into(), Opcode::INR_A.into()];
+        let mut cpu = CPU::new(data);
 
+        cpu.run_once();
+        cpu.run_once();
+        assert_eq!(cpu.is_halted(), true);
+        cpu.run_once();
         assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.data[3], 255);
+        assert_eq!(cpu.acc, 0);
+
+        cpu.resume();
+        assert_eq!(cpu.is_halted(), false);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.acc, 1);
     }
 
     #[test]
-    fn test_STA() {
-        let data = vec![Opcode::STA.into(), 3, 0, 0];
+    fn test_interrupt_wakes_halted() {
+        let data = vec![Opcode::HLT.into(), 0, 0, 0];
         let mut cpu = CPU::new(data);
 
-        cpu.acc = 255;
         cpu.run_once();
-
+        assert_eq!(cpu.is_halted(), true);
+        cpu.run_once();
+        assert_eq!(cpu.is_halted(), true);
+        cpu.send_interrupt(3);
+        cpu.run_once();
+        assert_eq!(cpu.is_halted(), false);
         assert_eq!(cpu.pc(), 3);
-        assert_eq!(cpu.data[3], 255);
+        assert_eq!(cpu.stack_pop(), 1);
     }
 
     #[test]
-    fn test_LDAX() {
-        let data = vec![Opcode::LDAX_B.into(), Opcode::LDAX_D.into(), 1, 255];
-
+    fn test_overflow() {
+        let data = vec![
+            Opcode::ADI.into(),
+            1,
+            Opcode::ADI.into(),
+            1,
+            Opcode::SUI.into(),
+            1,
+            Opcode::CMP_B.into(),
+        ];
         let mut cpu = CPU::new(data);
-        cpu.registers[Register::B as usize] = 0;
-        cpu.registers[Register::C as usize] = 2;
-        cpu.acc = 0;
+
+        cpu.acc = 0x7F;
         cpu.run_once();
+        assert_eq!(cpu.acc, 0x80);
+        assert_eq!(cpu.last_add_overflowed(), true);
 
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 1);
+        cpu.acc = 0x01;
+        cpu.run_once();
+        assert_eq!(cpu.last_add_overflowed(), false);
 
-        cpu.registers[Register::D as usize] = 0;
-        cpu.registers[Register::E as usize] = 3;
-        cpu.acc = 0;
+        cpu.acc = 0x80;
         cpu.run_once();
+        assert_eq!(cpu.acc, 0x7F);
+        assert_eq!(cpu.last_sub_overflowed(), true);
 
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 255);
+        cpu.acc = 0x00;
+        cpu.registers[Register::B as usize] = 0x01;
+        cpu.run_once();
+        assert_eq!(cpu.last_sub_overflowed(), false);
     }
 
     #[test]
-    fn test_LDA() {
-        let data = vec![Opcode::LDA.into(), 3, 0, 255];
-
+    fn test_branch_cycles() {
+        let data = vec![
+            Opcode::CZ.into(),
+            0,
+            0,
+            Opcode::JZ.into(),
+            0,
+            0,
+            Opcode::RNZ.into(),
+            Opcode::RZ.into(),
+            0,
+            0,
+        ];
         let mut cpu = CPU::new(data);
-        cpu.run_once();
 
+        let step = cpu.step_debug();
+        assert_eq!(step.opcode, Opcode::CZ);
+        assert_eq!((step.cycles, step.branch_taken), (11, Some(false)));
+        assert_eq!(cpu.step_debug().cycles, 10);
+
+        cpu.flag.set_zero_flag(true);
+        cpu.set_pc(0);
+        let step = cpu.step_debug();
+        assert_eq!((step.cycles, step.branch_taken), (17, Some(true)));
+        cpu.set_pc(3);
+        let step = cpu.step_debug();
+        assert_eq!((step.cycles, step.branch_taken), (10, Some(true)));
+
+        cpu.set_pc(6);
+        assert_eq!(cpu.step_debug().cycles, 5);
+        let step = cpu.step_debug();
+        assert_eq!((step.cycles, step.branch_taken), (11, Some(true)));
         assert_eq!(cpu.pc(), 3);
-        assert_eq!(cpu.acc, 255);
+        assert_eq!(cpu.cycles(), 11 + 10 + 17 + 10 + 5 + 11);
+
+        cpu.set_pc(0);
+        assert_eq!(cpu.step_debug().branch_taken, Some(true));
+        cpu.set_pc(1);
+        assert_eq!(cpu.execute(Opcode::ADD_B), 4);
     }
 
     #[test]
-    fn test_INX() {
-        let data = vec![Opcode::INX_D.into(), Opcode::INX_SP.into()];
+    fn test_dirty_tracking() {
+        let mut data = vec![0; 0x14];
+        data[..5].copy_from_slice(&[
+            Opcode::MOV_MA.into(),
+            Opcode::STA.into(),
+            0x12,
+            0x00,
+            Opcode::PUSH_B.into(),
+        ]);
         let mut cpu = CPU::new(data);
+        cpu.enable_dirty_tracking(0x08, 0x14);
+        cpu.set_memory_address(0x10);
 
-        cpu.registers[Register::D as usize] = 0x38;
-        cpu.registers[Register::E as usize] = 0xff;
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.registers[Register::D as usize], 0x39);
-        assert_eq!(cpu.registers[Register::E as usize], 0x00);
-
-        let sp = cpu.sp();
         cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(sp + 1, cpu.sp());
+        cpu.run_once();
+        cpu.set_value(0x09, 1);
+        cpu.set_value(0x02, 1);
+        assert_eq!(
+            cpu.take_dirty_ranges(),
+            vec![0x09..0x0A, 0x10..0x11, 0x12..0x14]
+        );
+        assert_eq!(cpu.take_dirty_ranges(), vec![]);
     }
 
     #[test]
-    fn test_DCX() {
-        let data = vec![Opcode::DCX_H.into(), Opcode::DCX_SP.into()];
-        let mut cpu = CPU::new(data);
-
-        cpu.registers[Register::H as usize] = 0x98;
-        cpu.registers[Register::L as usize] = 0x00;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.registers[Register::H as usize], 0x97);
-        assert_eq!(cpu.registers[Register::L as usize], 0xff);
+    fn test_architectural_eq() {
+        let data = vec![Opcode::MVI_B.into(), 7, Opcode::PUSH_B.into(), 0, 0];
+        let mut a = CPU::new(data.clone());
+        let mut b = CPU::new(data);
+        a.enable_trace(io::sink());
+        a.add_breakpoint(0);
+
+        a.run_once();
+        a.run_once();
+        b.run_once();
+        assert!(!a.architectural_eq(&b));
+        b.run_once();
+        assert!(a.architectural_eq(&b));
+        assert_eq!(a.state(), b.state());
+
+        let state = a.state();
+        a.run_once();
+        a.restore_state(&state);
+        assert_eq!(a.state(), state);
+        a.set_value(0, 0);
+        assert!(!a.architectural_eq(&b));
+    }
 
-        let sp = cpu.sp();
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(sp - 1, cpu.sp());
+    #[test]
+    fn test_load_segments() {
+        let mut cpu = CPU::new(vec![0; 0x0200]);
+
+        cpu.load_segments(&[(0x0000, &[0xC3, 0x00, 0x01]), (0x0100, &[0x76])])
+            .unwrap();
+        assert_eq!(&cpu.data[..3], &[0xC3, 0x00, 0x01]);
+        assert_eq!(cpu.data[0x0100], 0x76);
+
+        assert!(matches!(
+            cpu.load_segments(&[(0x0010, &[1, 2, 3]), (0x0012, &[4])]),
+            Err(Error::OverlappingSegments)
+        ));
+        assert!(matches!(
+            cpu.load_segments(&[(0x01FF, &[1, 2])]),
+            Err(Error::OutOfBounds)
+        ));
+        assert_eq!(cpu.data[0x0010], 0);
     }
 
     #[test]
-    fn test_INR() {
-        let data = vec![Opcode::INR_C.into()];
+    fn test_run_once_checked() {
+        let data = vec![Opcode::NOP.into(), 0x08, Opcode::JMP.into(), 0];
         let mut cpu = CPU::new(data);
-        cpu.registers[Register::C as usize] = 0x99;
-        cpu.run_once();
 
+        assert_eq!(cpu.run_once_checked().unwrap(), Opcode::NOP);
+        assert!(matches!(cpu.run_once_checked(), Err(Error::IllegalValue)));
         assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.registers[Register::C as usize], 0x9A);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), true);
+
+        cpu.set_pc(2);
+        assert!(matches!(cpu.run_once_checked(), Err(Error::OutOfBounds)));
+        cpu.set_pc(4);
+        assert!(matches!(cpu.run_once_checked(), Err(Error::OutOfBounds)));
     }
 
     #[test]
-    fn test_DCR() {
-        let data = vec![Opcode::DCR_M.into(), 0x40];
+    fn test_halt_with_interrupts_disabled() {
+        let data = vec![Opcode::DI.into(), Opcode::HLT.into(), 0, 0];
         let mut cpu = CPU::new(data);
-        cpu.set_memory_address(1);
-        cpu.run_once();
 
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.data[1], 0x3f);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), false);
+        cpu.run_once();
+        cpu.request_interrupt(1);
+        cpu.run_until_halt();
+        assert_eq!(cpu.is_halted(), true);
+        assert_eq!(cpu.has_pending_interrupt(), true);
+        cpu.run_once();
+        cpu.run_once();
+        assert_eq!(cpu.is_halted(), true);
+        assert_eq!(cpu.pc(), 2);
     }
 
     #[test]
-    fn test_MVI() {
-        let data = vec![Opcode::MVI_A.into(), 1, Opcode::MVI_M.into(), 255];
+    fn test_latched_interrupt() {
+        let mut data = vec![0; 0x10];
+        data[..4].copy_from_slice(&[
+            Opcode::DI.into(),
+            Opcode::NOP.into(),
+            Opcode::EI.into(),
+            Opcode::HLT.into(),
+        ]);
+        data[0x08..0x0B].copy_from_slice(&[Opcode::MVI_A.into(), 1, Opcode::HLT.into()]);
         let mut cpu = CPU::new(data);
 
-        cpu.acc = 0;
+        cpu.run_once();
+        cpu.request_interrupt(1);
         cpu.run_once();
         assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 1);
+        assert_eq!(cpu.has_pending_interrupt(), true);
 
-        cpu.set_memory_address(1);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 4);
-        assert_eq!(cpu.data[1], 255);
+        assert_eq!(cpu.step_debug().opcode, Opcode::RST_1);
+        assert_eq!(cpu.pc(), 8);
+        assert_eq!(cpu.has_pending_interrupt(), false);
+
+        cpu.run_until_halt();
+        assert_eq!(cpu.acc, 1);
+        assert_eq!(cpu.stack_pop(), 3);
     }
 
     #[test]
-    fn test_RLC() {
-        let data = vec![Opcode::RLC.into()];
+    fn test_register_pair_addresses() {
+        let data = vec![Opcode::MOV_AM.into(), 0, 0, 0];
         let mut cpu = CPU::new(data);
+        cpu.registers = [0x01, 0x02, 0x03, 0x04, 0x00, 0x03];
 
-        cpu.acc = 0xf2;
+        assert_eq!(cpu.bc_addr(), 0x0102);
+        assert_eq!(cpu.de_addr(), 0x0304);
+        assert_eq!(cpu.hl_addr(), 3);
+
+        cpu.write_at_hl(0x42);
+        assert_eq!(cpu.read_at_hl(), 0x42);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 0xe5);
-        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.acc, 0x42);
     }
 
     #[test]
-    fn test_RRC() {
-        let data = vec![Opcode::RRC.into()];
-        let mut cpu = CPU::new(data);
-
-        cpu.acc = 0xf2;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 0x79);
-        assert_eq!(cpu.flag.carry_flag(), false);
+    fn test_opcode_profile() {
+        let code = assemble(
+            "
+                    MVI B, 10
+            loop:   JMP body
+            body:   DCR B
+                    JNZ loop
+                    HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
+        cpu.enable_opcode_profiling();
+        cpu.run_until_halt();
+
+        let profile = cpu.opcode_profile();
+        assert_eq!(profile[Opcode::JMP as usize], 10);
+        assert_eq!(profile[Opcode::MVI_B as usize], 1);
+        assert_eq!(
+            cpu.profile_report(),
+            "DCR   10\nJMP   10\nJNZ   10\nHLT   1\nMVI   1\n"
+        );
     }
 
     #[test]
-    fn test_RAL() {
-        let data = vec![Opcode::RAL.into()];
+    fn test_initial_sp() {
+        let mut data = vec![0; 0x10000];
+        data[0] = Opcode::PUSH_B.into();
         let mut cpu = CPU::new(data);
+        assert_eq!(cpu.memory_size(), 0x10000);
+        assert_eq!(cpu.sp(), 0);
 
-        cpu.acc = 0xb5;
+        cpu.registers[Register::B as usize] = 0x12;
+        cpu.registers[Register::C as usize] = 0x34;
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 0x6a);
-        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.sp(), 0xFFFE);
+        assert_eq!(cpu.get_value(0xFFFF), 0x12);
+        assert_eq!(cpu.get_value(0xFFFE), 0x34);
+
+        assert_eq!(CPU::new(vec![0; 0x10100]).sp(), 0);
+        assert_eq!(CPU::new(vec![0; 0x0100]).sp(), 0x0100);
     }
 
     #[test]
-    fn test_RAR() {
-        let data = vec![Opcode::RAR.into()];
-        let mut cpu = CPU::new(data);
+    fn test_run_with() {
+        let code = assemble(
+            "
+            loop:   INR A
+                    JMP loop
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
+
+        let mut steps = 0;
+        cpu.run_with(|cpu| {
+            steps += 1;
+            if cpu.acc == 3 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(steps, 5);
 
-        cpu.acc = 0x6a;
-        cpu.flag.set_carry_flag(true);
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 0xb5);
-        assert_eq!(cpu.flag.carry_flag(), false);
+        cpu.set_value(0, Opcode::HLT.into());
+        cpu.set_pc(0);
+        cpu.run_with(|_| ControlFlow::Continue(()));
+        assert_eq!(cpu.is_halted(), true);
     }
 
     #[test]
-    fn test_SHLD() {
-        let data = vec![Opcode::SHLD.into(), 3, 0, 0, 0];
-        let mut cpu = CPU::new(data);
-        cpu.registers[Register::L as usize] = 1;
-        cpu.registers[Register::H as usize] = 2;
+    fn test_instruction_count() {
+        let code = assemble(
+            "
+            MVI A, 1
+            INR A
+            MOV B, A
+            NOP
+            HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
 
+        cpu.run_until_halt();
         cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
-        assert_eq!(cpu.data[3], 1);
-        assert_eq!(cpu.data[4], 2);
+        assert_eq!(cpu.instruction_count(), 5);
+        assert_eq!(cpu.cycles(), 7 + 5 + 5 + 4 + 7);
+
+        cpu.reset();
+        assert_eq!(cpu.instruction_count(), 0);
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(cpu.pc(), 0);
+        assert_eq!(cpu.is_halted(), false);
+        assert_eq!(cpu.registers[Register::B as usize], 2);
     }
 
     #[test]
-    fn test_LHLD() {
-        let data = vec![Opcode::LHLD.into(), 3, 0, 1, 2];
+    fn test_illegal_opcode_handler() {
+        let data = vec![0x08, Opcode::INR_A.into(), 0xCB];
         let mut cpu = CPU::new(data);
-
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
-        assert_eq!(cpu.registers[Register::L as usize], 1);
-        assert_eq!(cpu.registers[Register::H as usize], 2);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+
+        let log = seen.clone();
+        cpu.set_illegal_opcode_handler(Box::new(move |cpu, n| {
+            log.borrow_mut().push(n);
+            if n == 0xCB {
+                cpu.halted = true;
+            } else {
+                cpu.set_pc(cpu.pc + 1);
+            }
+        }));
+        assert_eq!(cpu.run_once_checked().unwrap(), Opcode::NOP);
+        assert_eq!(cpu.run_once_checked().unwrap(), Opcode::INR_A);
+        assert_eq!(cpu.run_once_checked().unwrap(), Opcode::JMP);
+        assert_eq!(cpu.is_halted(), true);
+        assert_eq!(cpu.acc, 1);
+        assert_eq!(*seen.borrow(), vec![0x08, 0xCB]);
     }
 
     #[test]
-    fn test_DAA() {
-        let data = vec![Opcode::DAA.into()];
+    fn test_register_pairs() {
+        let code = assemble(
+            "
+            LXI B, 0x1122
+            LXI D, 0x3344
+            LXI H, 0x5566
+            INX B
+            DCX D
+            DAD B
+            PUSH B
+            PUSH D
+            PUSH H
+            POP B
+            POP D
+            POP H
+            HLT
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x0100];
+        data[..code.len()].copy_from_slice(&code);
         let mut cpu = CPU::new(data);
 
-        cpu.acc = 0x9b;
-        cpu.run_once();
-
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 1);
-        assert_eq!(cpu.flag.carry_flag(), true);
-        assert_eq!(cpu.flag.auxiliary_flag(), true);
+        cpu.run_until_halt();
+        assert_eq!(cpu.registers, [0x66, 0x89, 0x33, 0x43, 0x11, 0x23]);
     }
 
     #[test]
-    fn test_DAD() {
-        // sp == 5
-        let data = vec![Opcode::DAD_B.into(), Opcode::DAD_SP.into(), 0, 0, 0];
-        let mut cpu = CPU::new(data);
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "register pair 3")]
+    fn test_reg_slots_rejects_sp() {
+        CPU::reg_slots(0b11);
+    }
 
-        cpu.registers[Register::B as usize] = 0x33;
-        cpu.registers[Register::C as usize] = 0x9f;
-        cpu.registers[Register::H as usize] = 0xa1;
-        cpu.registers[Register::L as usize] = 0x7b;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.registers[Register::H as usize], 0xd5);
-        assert_eq!(cpu.registers[Register::L as usize], 0x1a);
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "register 7 is not in `registers`")]
+    fn test_reg_rejects_acc() {
+        CPU::new(vec![0]).reg(Register::Acc as usize);
+    }
 
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.registers[Register::H as usize], 0xd5);
-        assert_eq!(cpu.registers[Register::L as usize], 0x1f);
+    #[test]
+    fn test_stack_frames() {
+        let mut cpu = CPU::new(vec![0; 0x10]);
+        cpu.stack_push(0x0103);
+        cpu.stack_push(0x0206);
+        cpu.stack_push(0x0309);
+
+        assert_eq!(cpu.stack_frames(2), vec![0x0309, 0x0206]);
+        assert_eq!(cpu.stack_frames(8), vec![0x0309, 0x0206, 0x0103]);
+        assert_eq!(cpu.sp(), 0x0A);
     }
 
     #[test]
-    fn test_STC() {
-        let data = vec![Opcode::STC.into()];
+    fn test_tick() {
+        let data = vec![
+            Opcode::INR_A.into(),
+            Opcode::HLT.into(),
+            0xDD,
+            Opcode::JMP.into(),
+        ];
         let mut cpu = CPU::new(data);
 
-        assert_eq!(cpu.flag.carry_flag(), false);
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.carry_flag(), true);
+        assert_eq!(cpu.tick(), Tick::Running);
+        assert_eq!(cpu.tick(), Tick::Halted);
+        assert_eq!(cpu.tick(), Tick::Halted);
+
+        cpu.resume();
+        assert_eq!(cpu.tick(), Tick::IllegalOpcode(0xDD));
+        assert_eq!(cpu.pc(), 2);
+        cpu.set_pc(3);
+        assert_eq!(cpu.tick(), Tick::OutOfBounds);
     }
 
     #[test]
-    fn test_CMA() {
-        let data = vec![Opcode::CMA.into()];
+    fn test_mem_trace() {
+        let mut data = assemble("LDA 0x10\nSTA 0x11").unwrap();
+        data.resize(0x12, 0);
+        data[0x10] = 0x42;
         let mut cpu = CPU::new(data);
-
-        cpu.acc = 0x51;
+        cpu.enable_mem_trace();
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 0xae);
+        cpu.run_once();
+
+        let access = |addr, value, kind| MemAccess { addr, value, kind };
+        assert_eq!(
+            cpu.take_mem_trace(),
+            vec![
+                access(0x00, 0x3A, AccessKind::Fetch),
+                access(0x01, 0x10, AccessKind::Fetch),
+                access(0x02, 0x00, AccessKind::Fetch),
+                access(0x10, 0x42, AccessKind::Read),
+                access(0x03, 0x32, AccessKind::Fetch),
+                access(0x04, 0x11, AccessKind::Fetch),
+                access(0x05, 0x00, AccessKind::Fetch),
+                access(0x11, 0x42, AccessKind::Write),
+            ]
+        );
+        assert!(cpu.take_mem_trace().is_empty());
     }
 
     #[test]
-    fn test_CMC() {
-        let data = vec![Opcode::CMC.into(), Opcode::CMC.into()];
-        let mut cpu = CPU::new(data);
+    fn test_alu() {
+        let (res, flag) = CPU::alu_add(0x3A, 0xC6, false);
+        assert_eq!(res, 0x00);
+        assert_eq!(flag.value(), 0b0101_0111);
+        let (res, flag) = CPU::alu_add(0x0F, 0x00, true);
+        assert_eq!(res, 0x10);
+        assert_eq!(flag.value(), 0b0001_0010);
+
+        let (res, flag) = CPU::alu_sub(0x10, 0x01, false);
+        assert_eq!(res, 0x0F);
+        assert_eq!(flag.value(), 0b0000_0110);
+        let (res, flag) = CPU::alu_sub(0x00, 0xFF, true);
+        assert_eq!(res, 0x00);
+        assert_eq!(flag.value(), 0b0100_0111);
+
+        assert_eq!(CPU::alu_and(0xF0, 0x08), (0x00, Flag::new(0b0101_0110)));
+        assert_eq!(CPU::alu_xor(0xFF, 0x0F), (0xF0, Flag::new(0b1000_0110)));
+        assert_eq!(CPU::alu_or(0x01, 0x02), (0x03, Flag::new(0b0000_0110)));
+    }
 
+    #[test]
+    fn test_wait_state_hook() {
+        let mut data = assemble("LDA 0x10\nSTA 0x8000").unwrap();
+        data.resize(0x10000, 0);
+        let mut cpu = CPU::new(data);
+        // everything above 0x8000 is slow I/O memory
+        cpu.set_wait_state_hook(Box::new(|addr, kind| match kind {
+            AccessKind::Fetch => 0,
+            _ if addr >= 0x8000 => 3,
+            _ => 1,
+        }));
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.carry_flag(), true);
-
+        assert_eq!(cpu.cycles(), 13 + 1);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.flag.carry_flag(), false);
+        assert_eq!(cpu.cycles(), 13 + 1 + 13 + 3);
     }
 
     #[test]
-    fn test_MOV() {
-        let data = vec![
-            Opcode::MOV_AE.into(),
-            Opcode::MOV_DD.into(),
-            Opcode::MOV_MA.into(),
-            0,
-        ];
+    fn test_rewind() {
+        let mut data = assemble(
+            "
+                    MVI B, 0
+            loop:   INR B
+                    MOV A, B
+                    STA 0x20
+                    PUSH B
+                    JMP loop
+            ",
+        )
+        .unwrap();
+        data.resize(0x40, 0);
         let mut cpu = CPU::new(data);
+        assert!(cpu.rewind(1).is_err());
+        cpu.enable_rewind(8);
 
-        cpu.registers[Register::E as usize] = 1;
-        cpu.registers[Register::D as usize] = 2;
-        cpu.set_memory_address(3);
+        for _ in 0..5 {
+            cpu.run_once();
+        }
+        let checkpoint = cpu.clone();
+        for _ in 0..5 {
+            cpu.run_once();
+        }
+        assert!(!cpu.architectural_eq(&checkpoint));
 
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 1);
+        cpu.rewind(5).unwrap();
+        assert!(cpu.architectural_eq(&checkpoint));
+        assert_eq!(cpu.state(), checkpoint.state());
+        assert_eq!(cpu.cycles(), checkpoint.cycles());
 
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.registers[Register::D as usize], 2);
+        // only 8 steps are kept
+        for _ in 0..10 {
+            cpu.run_once();
+        }
+        cpu.rewind(20).unwrap();
+        assert_eq!(cpu.instruction_count(), 7);
+    }
 
-        cpu.acc = 3;
+    #[test]
+    fn test_interrupt_cycles() {
+        let mut cpu = CPU::new(vec![0; 0x40]);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
-        assert_eq!(cpu.data[3], 3);
+        cpu.request_interrupt(1);
+        cpu.run_once();
+        assert_eq!(cpu.cycles(), 4 + 11);
+        assert_eq!(cpu.pc(), 0x08);
+        assert_eq!(cpu.interrupts_serviced(), 1);
+        assert_eq!(cpu.instruction_count(), 1);
     }
 
     #[test]
-    fn test_HLT() {
-        let data = vec![Opcode::HLT.into()];
-        let mut cpu = CPU::new(data);
+    fn test_compare() {
+        // the examples from the 8080 manual, with E = 0x05
+        let flag = CPU::compare(0x0A, 0x05);
+        assert_eq!(flag.carry_flag(), false);
+        assert_eq!(flag.zero_flag(), false);
+        let flag = CPU::compare(0x02, 0x05);
+        assert_eq!(flag.carry_flag(), true);
+        assert_eq!(flag.zero_flag(), false);
+        let flag = CPU::compare(0xE5, 0x05);
+        assert_eq!(flag.carry_flag(), false);
+        assert_eq!(flag.zero_flag(), false);
+
+        let flag = CPU::compare(0x05, 0x05);
+        assert_eq!(flag.zero_flag(), true);
+        assert_eq!(flag.auxiliary_flag(), true);
+        assert_eq!(CPU::compare(0x10, 0x01).auxiliary_flag(), false);
+    }
 
-        assert_eq!(cpu.halted, false);
+    #[test]
+    fn test_psw() {
+        let mut cpu = CPU::new(vec![Opcode::PUSH_PSW.into(), 0, 0, 0]);
+        cpu.acc = 0x12;
+        cpu.set_flags(Flag::new(0b1100_0111));
+        assert_eq!(cpu.flags(), Flag::new(0b1100_0111));
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.halted, true);
+        assert_eq!(cpu.psw(), 0x12C7);
+        assert_eq!(cpu.read_u16(cpu.sp()), cpu.psw());
     }
 
     #[test]
-    fn test_ADD() {
-        let data = vec![Opcode::ADD_D.into(), Opcode::ADD_M.into(), 2];
-        let mut cpu = CPU::new(data);
+    fn test_execute_byte() {
+        let mut cpu = CPU::new(vec![0x00, 0x34, 0x12]);
+        cpu.execute_byte(Opcode::LXI_H.into()).unwrap();
+        assert_eq!(cpu.hl_addr(), 0x1234);
+        assert_eq!(cpu.pc(), 3);
 
-        cpu.registers[Register::D as usize] = 0x2e;
-        cpu.acc = 0x6c;
-        cpu.run_once();
+        cpu.set_pc(1);
+        assert!(matches!(
+            cpu.execute_byte(Opcode::JMP.into()),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(cpu.execute_byte(0xDD), Err(Error::IllegalValue)));
         assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.acc, 0x9a);
+    }
 
-        cpu.set_memory_address(2);
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.acc, 0x9c);
+    #[test]
+    fn test_carry_in_aux() {
+        // (opcode, A, operand in B or the immediate, result, AC, CY), all with CY set
+        let cases = [
+            (Opcode::ADC_B, 0x0F, 0x00, 0x10, true, false),
+            (Opcode::ADC_B, 0x07, 0x08, 0x10, true, false),
+            (Opcode::ADC_B, 0x00, 0x00, 0x01, false, false),
+            (Opcode::ACI, 0x0E, 0x01, 0x10, true, false),
+            (Opcode::ACI, 0xFF, 0x00, 0x00, true, true),
+            (Opcode::SBB_B, 0x10, 0x00, 0x0F, false, false),
+            (Opcode::SBB_B, 0x00, 0xFF, 0x00, false, true),
+            (Opcode::SBB_B, 0x11, 0x00, 0x10, true, false),
+            (Opcode::SBI, 0x1F, 0x0F, 0x0F, false, false),
+            (Opcode::SBI, 0x1F, 0x0E, 0x10, true, false),
+        ];
+        for &(opcode, acc, operand, res, aux, carry) in cases.iter() {
+            let mut cpu = CPU::new(vec![opcode.into(), operand]);
+            cpu.acc = acc;
+            cpu.registers[Register::B as usize] = operand;
+            cpu.flag.set_carry_flag(true);
+            cpu.run_once();
+            assert_eq!(cpu.acc, res, "{:?} {:02X}, {:02X}", opcode, acc, operand);
+            assert_eq!(
+                cpu.flag.auxiliary_flag(),
+                aux,
+                "{:?} {:02X}, {:02X}",
+                opcode,
+                acc,
+                operand
+            );
+            assert_eq!(
+                cpu.flag.carry_flag(),
+                carry,
+                "{:?} {:02X}, {:02X}",
+                opcode,
+                acc,
+                operand
+            );
+        }
     }
 
     #[test]
-    fn test_ADC() {
-        let data = vec![Opcode::ADC_C.into(), Opcode::ADC_C.into()];
-        let mut cpu = CPU::new(data);
+    fn test_peek() {
+        let mut cpu = CPU::new(vec![Opcode::MVI_B.into(), 0x12, 0xDD, Opcode::JMP.into()]);
+        let next = cpu.peek().unwrap();
+        assert_eq!(next, (Opcode::MVI_B, "MVI B,$12".to_string()));
+        assert_eq!(cpu.peek().unwrap(), next);
+        assert_eq!(cpu.pc(), 0);
 
-        cpu.registers[Register::C as usize] = 0x3d;
-        cpu.acc = 0x42;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
-        assert_eq!(cpu.acc, 0x7f);
+        cpu.set_pc(2);
+        assert!(matches!(cpu.peek(), Err(Error::IllegalValue)));
+        cpu.set_pc(3);
+        assert!(matches!(cpu.peek(), Err(Error::OutOfBounds)));
+    }
 
-        cpu.registers[Register::C as usize] = 0x3d;
-        cpu.acc = 0x42;
-        cpu.flag.set_carry_flag(true);
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.flag.auxiliary_flag(), true);
-        assert_eq!(cpu.acc, 0x80);
+    #[test]
+    fn test_interrupt_vector_base() {
+        let mut cpu = CPU::new(vec![0; 0x0200]);
+        cpu.set_interrupt_vector_base(0x0100);
+        cpu.request_interrupt(1);
+        let step = cpu.step_debug();
+        assert_eq!(cpu.pc(), 0x0108);
+        assert_eq!(step.opcode, Opcode::RST_1);
+        assert_eq!(step.cycles, 11);
     }
 
     #[test]
-    fn test_SUB() {
-        let data = vec![Opcode::SUB_B.into()];
-        let mut cpu = CPU::new(data);
+    fn test_dump_state_json() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
 
-        cpu.acc = 0x3e;
-        cpu.registers[Register::B as usize] = 0x3e;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.acc, 0);
-        assert_eq!(cpu.flag.zero_flag(), true);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), true);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
+        let mut cpu = CPU::new(b"123456789".to_vec());
+        cpu.set_pc(0x0100);
+        cpu.acc = 0x12;
+        cpu.registers[Register::L as usize] = 0xFF;
+        let json = cpu.dump_state_json();
+        for key in [
+            "pc", "sp", "a", "b", "c", "d", "e", "h", "l", "flags", "mem_crc",
+        ]
+        .iter()
+        {
+            assert!(json.contains(&format!("\"{}\":", key)), "{} missing", key);
+        }
+        assert_eq!(
+            json,
+            format!(
+                "{{\"pc\":256,\"sp\":9,\"a\":18,\"b\":0,\"c\":0,\"d\":0,\"e\":0,\"h\":0,\"l\":255,\"flags\":2,\"mem_crc\":{}}}",
+                0xCBF4_3926u32
+            )
+        );
     }
 
     #[test]
-    fn test_SBB() {
-        let data = vec![Opcode::SBB_L.into()];
+    fn test_small_memory_wraps() {
+        let mut data = assemble(
+            "
+                    LXI H, 0xF010
+                    MVI M, 0x42
+                    LDA 0x1010
+                    LXI SP, 0
+                    PUSH H
+                    HLT
+            ",
+        )
+        .unwrap();
+        data.resize(0x1000, 0);
         let mut cpu = CPU::new(data);
+        cpu.run_until_halt();
+        assert_eq!(cpu.get_value(0x0010), 0x42);
+        assert_eq!(cpu.acc, 0x42);
+        assert_eq!(cpu.get_value(0xFFFF), 0xF0);
+        assert_eq!(cpu.read_u16(0x0FFE), 0xF010);
+    }
 
-        cpu.registers[Register::L as usize] = 2;
-        cpu.acc = 4;
-        cpu.flag.set_carry_flag(true);
+    #[test]
+    fn test_MOV_self() {
+        let moves = [
+            Opcode::MOV_BB,
+            Opcode::MOV_CC,
+            Opcode::MOV_DD,
+            Opcode::MOV_EE,
+            Opcode::MOV_HH,
+            Opcode::MOV_LL,
+            Opcode::MOV_AA,
+        ];
+        for &opcode in moves.iter() {
+            let mut cpu = CPU::new(vec![opcode.into()]);
+            cpu.registers = [1, 2, 3, 4, 5, 6];
+            cpu.acc = 7;
+            let before = cpu.state();
+            // the datasheet gives 5 T-states for every register-to-register MOV
+            assert_eq!(cpu.execute(opcode), 5, "{:?}", opcode);
+            assert_eq!(cpu.pc(), 1);
+            assert_eq!(
+                CpuState {
+                    pc: 0,
+                    ..cpu.state()
+                },
+                before,
+                "{:?} changed state",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn test_MOV_M_M_is_HLT() {
+        assert_eq!(Opcode::from(0x76), Opcode::HLT);
+        assert_eq!(Opcode::HLT.mnemonic(), "HLT");
+        assert_eq!(Opcode::HLT.cycles(), (7, 7));
 
+        let mut cpu = CPU::new(vec![0x76, 0]);
+        cpu.set_memory_address(1);
         cpu.run_once();
+        assert!(cpu.is_halted());
         assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), true);
-        assert_eq!(cpu.acc, 1);
     }
 
     #[test]
-    fn test_ANA() {
-        let data = vec![Opcode::ANA_C.into()];
+    fn test_strict_flags() {
+        let run = |strict: bool, opcode: Opcode, acc: u8, operand: u8| {
+            let mut cpu = CPU::new(vec![opcode.into(), operand]);
+            cpu.set_strict_flags(strict);
+            cpu.acc = acc;
+            cpu.registers[Register::B as usize] = operand;
+            cpu.run_once();
+            cpu.flag.auxiliary_flag()
+        };
+        // 0x20 - 0x01 borrows from the low nibble, but 0x20 >= 0x01
+        assert!(!run(true, Opcode::SUB_B, 0x20, 0x01));
+        assert!(run(false, Opcode::SUB_B, 0x20, 0x01));
+        assert!(!run(true, Opcode::CPI, 0x20, 0x01));
+        assert!(run(false, Opcode::CPI, 0x20, 0x01));
+        assert!(run(true, Opcode::ANI, 0x08, 0x00));
+        assert!(!run(false, Opcode::ANI, 0x08, 0x00));
+        assert!(run(false, Opcode::ADD_B, 0x0F, 0x01));
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut data = vec![0; 0x0100];
+        data[0x08] = Opcode::HLT.into();
         let mut cpu = CPU::new(data);
+        cpu.set_pc(0x10);
+
+        assert_eq!(cpu.run_frame(200, Some((100, 1))), 0);
+        assert_eq!(cpu.cycles(), 200);
+        assert_eq!(cpu.interrupts_serviced(), 1);
+        // 25 NOPs ran before the interrupt, and the CPU has halted in its handler
+        assert_eq!(cpu.stack_frames(1), vec![0x10 + 25]);
+        assert!(cpu.is_halted());
+
+        // a halted CPU idles until the mid-frame interrupt wakes it
+        let mut data = vec![0; 0x0100];
+        data[0x20] = Opcode::HLT.into();
+        let mut cpu = CPU::new(data);
+        cpu.set_pc(0x20);
+        assert_eq!(cpu.run_frame(100, Some((50, 2))), 1);
+        assert_eq!(cpu.stack_frames(1), vec![0x21]);
+        // HLT, idle to 50, RST 2 and ten NOPs
+        assert_eq!(cpu.cycles(), 50 + 11 + 10 * 4);
+
+        let mut cpu = CPU::new(vec![0; 0x10]);
+        assert_eq!(cpu.run_frame(10, None), 2);
+    }
 
-        cpu.registers[Register::C as usize] = 0x0f;
-        cpu.acc = 0xfc;
+    #[test]
+    fn test_mem_bits() {
+        let mut cpu = CPU::new(vec![0b0100_0000, 0]);
+        assert!(!cpu.get_mem_bit(0, 0).unwrap());
+        assert!(cpu.get_mem_bit(0, 6).unwrap());
+
+        cpu.set_mem_bit(0, 0, true).unwrap();
+        cpu.set_mem_bit(0, 7, true).unwrap();
+        cpu.set_mem_bit(0, 6, false).unwrap();
+        assert_eq!(cpu.get_value(0), 0b1000_0001);
+        assert!(cpu.get_mem_bit(0, 7).unwrap());
+
+        assert!(matches!(
+            cpu.set_mem_bit(0, 8, true),
+            Err(Error::IllegalValue)
+        ));
+        assert!(matches!(cpu.get_mem_bit(0, 8), Err(Error::IllegalValue)));
+        assert!(matches!(cpu.get_mem_bit(2, 0), Err(Error::OutOfBounds)));
+        assert_eq!(cpu.get_value(0), 0b1000_0001);
+    }
 
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.acc, 0x0c);
+    #[test]
+    fn test_register_file() {
+        let mut cpu = CPU::new(vec![0; 4]);
+        cpu.set_registers([1, 2, 3, 4, 5, 6]);
+        cpu.set_acc(7);
+        assert_eq!(cpu.registers(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(cpu.acc, 7);
+
+        let state = CpuState {
+            registers: [6, 5, 4, 3, 2, 1],
+            acc: 0x12,
+            flag: Flag::new(0x47),
+            sp: 0x1234,
+            pc: 0x5678,
+            halted: true,
+            interrupts_enabled: false,
+        };
+        cpu.load_regs(&state);
+        assert_eq!(cpu.registers(), state.registers);
+        assert_eq!(cpu.psw(), 0x1247);
+        assert_eq!(cpu.sp(), 4);
+        assert_eq!(cpu.pc(), 0);
+        assert!(!cpu.is_halted());
     }
 
     #[test]
-    fn test_XRA() {
-        let data = vec![Opcode::XRA_B.into()];
+    fn test_schedule() {
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new(vec![0; 0x0100]);
+        let log = fired.clone();
+        cpu.schedule(
+            100,
+            Box::new(move |cpu: &mut CPU| log.borrow_mut().push(cpu.cycles())),
+        );
+        for _ in 0..60 {
+            cpu.run_once();
+        }
+        assert_eq!(*fired.borrow(), vec![100]);
+
+        // a scheduled interrupt wakes a halted CPU in the middle of a frame
+        let mut data = vec![0; 0x0100];
+        data[0x20] = Opcode::HLT.into();
         let mut cpu = CPU::new(data);
+        cpu.set_pc(0x20);
+        cpu.schedule(40, Box::new(|cpu: &mut CPU| cpu.request_interrupt(1)));
+        cpu.run_frame(100, None);
+        assert_eq!(cpu.interrupts_serviced(), 1);
+        assert_eq!(cpu.stack_frames(1), vec![0x21]);
+        assert_eq!(cpu.cycles(), 40 + 11 + 13 * 4);
+    }
 
-        cpu.registers[Register::B as usize] = 0b0101_1100;
-        cpu.acc = 0b0111_1000;
+    #[test]
+    fn test_trace_iter() {
+        let code = assemble(
+            "
+                    MVI B, 2
+            loop:   DCR B
+                    JNZ loop
+                    HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
+        let trace = cpu
+            .trace_iter()
+            .map(|r| (r.pc, r.opcode, r.branch_taken))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trace,
+            vec![
+                (0, Opcode::MVI_B, None),
+                (2, Opcode::DCR_B, None),
+                (3, Opcode::JNZ, Some(true)),
+                (2, Opcode::DCR_B, None),
+                (3, Opcode::JNZ, Some(false)),
+                (6, Opcode::HLT, None),
+            ]
+        );
+        assert_eq!(cpu.trace_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let code = vec![
+            Opcode::IN.into(),
+            7,
+            Opcode::IN.into(),
+            7,
+            Opcode::IN.into(),
+            1,
+        ];
+        let mut cpu = CPU::new(code.clone());
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0xFF);
+        cpu.set_open_bus(0x00);
+        cpu.run_once();
+        assert_eq!(cpu.acc, 0x00);
 
+        let mut bus = IoBus::new();
+        bus.attach(
+            1,
+            Box::new(crate::device::ConsoleInput::blocking(&b"x"[..], 1)),
+        );
+        let mut cpu = CPU::with_devices(code, bus);
+        cpu.set_open_bus(0x5A);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        //        assert_eq!(cpu.flag.auxiliary_flag(), false);
-        assert_eq!(cpu.acc, 0b0010_0100);
+        assert_eq!(cpu.acc, 0x5A);
+        cpu.run_once();
+        cpu.run_once();
+        assert_eq!(cpu.acc, b'x');
     }
 
     #[test]
-    fn test_ORA() {
-        let data = vec![Opcode::ORA_C.into()];
-        let mut cpu = CPU::new(data);
+    fn test_try_stack() {
+        let mut cpu = CPU::new(vec![0; 8]);
+        for val in 1..=4 {
+            cpu.try_stack_push(val).unwrap();
+        }
+        assert_eq!(cpu.sp(), 0);
+        assert!(matches!(cpu.try_stack_push(5), Err(Error::StackOverflow)));
+        assert_eq!(cpu.sp(), 0);
 
-        cpu.registers[Register::C as usize] = 0x0f;
-        cpu.acc = 0x33;
+        for val in (1..=4).rev() {
+            assert_eq!(cpu.try_stack_pop().unwrap(), val);
+        }
+        assert!(matches!(cpu.try_stack_pop(), Err(Error::StackUnderflow)));
+        assert_eq!(cpu.sp(), 8);
+
+        // a full 64 KiB stack starts at 0 and wraps to the top of memory
+        let mut cpu = CPU::default();
+        cpu.try_stack_push(0x1234).unwrap();
+        assert_eq!(cpu.sp(), 0xFFFE);
+        assert_eq!(cpu.try_stack_pop().unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn test_update_flags() {
+        let mut cpu = CPU::new(vec![
+            Opcode::ADD_B.into(),
+            Opcode::ORA_A.into(),
+            Opcode::CPI.into(),
+            0x00,
+        ]);
+        cpu.set_update_flags(false);
+        cpu.acc = 0xFF;
+        cpu.registers[Register::B as usize] = 0x01;
+        cpu.set_flags(Flag::new(0b1000_0110));
 
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
-        assert_eq!(cpu.acc, 0x3f);
+        assert_eq!(cpu.acc, 0x00);
+        assert_eq!(cpu.flags().value(), 0b1001_0111);
+        cpu.run_once();
+        assert_eq!(cpu.flags().value(), 0b1000_0110);
+        // compares still set every flag
+        cpu.run_once();
+        assert_eq!(cpu.flags().value(), 0b0101_0110);
     }
 
     #[test]
-    fn test_CMP() {
-        let data = vec![Opcode::CMP_E.into()];
-        let mut cpu = CPU::new(data);
-
-        cpu.registers[Register::E as usize] = 0x5;
-        cpu.acc = 0xa;
+    fn test_pair() {
+        let mut cpu = CPU::new(vec![Opcode::DAD_SP.into(), Opcode::INX_SP.into()]);
+        cpu.set_pair(RegisterPair::BC, 0x1234);
+        cpu.set_pair(RegisterPair::HL, 0x8000);
+        cpu.set_pair(RegisterPair::SP, 0x8001);
+        assert_eq!(cpu.registers[..2], [0x12, 0x34]);
+        assert_eq!(cpu.pair(RegisterPair::BC), 0x1234);
+        assert_eq!(cpu.sp, 0x8001);
 
         cpu.run_once();
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), true);
-        assert_eq!(cpu.acc, 0xa);
+        assert_eq!(cpu.pair(RegisterPair::HL), 0x0001);
+        assert!(cpu.flag.carry_flag());
+        cpu.run_once();
+        assert_eq!(cpu.pair(RegisterPair::SP), 0x8002);
     }
 
     #[test]
-    fn test_RET() {
-        let data = vec![Opcode::RET.into(), 0, 0, Opcode::NOP.into(), 0, 0];
+    fn test_pc_wraps() {
+        let mut data = vec![0; 0x10000];
+        data[0xFFFD] = Opcode::JMP.into();
+        data[0xFFFE] = 0x34;
+        data[0xFFFF] = 0x12;
         let mut cpu = CPU::new(data);
+        cpu.set_pc(0xFFFD);
+        assert_eq!(cpu.peek().unwrap().1, "JMP $1234");
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 0x1234);
 
-        // return to nop
-        cpu.stack_push(3);
-        assert_eq!(cpu.data[cpu.data.len() - 2], 3);
+        // operands straddling the top come from 0x0000 and 0x0001
+        let mut data = vec![0; 0x10000];
+        data[0xFFFF] = Opcode::JMP.into();
+        data[0x0000] = 0x78;
+        data[0x0001] = 0x56;
+        let mut cpu = CPU::new(data);
+        cpu.set_pc(0xFFFF);
+        cpu.enable_trace(io::sink());
+        assert_eq!(cpu.run_once_checked().unwrap(), Opcode::JMP);
+        assert_eq!(cpu.pc(), 0x5678);
+
+        // and PC itself wraps past the last byte
+        let mut cpu = CPU::default();
+        cpu.set_pc(0xFFFF);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.pc(), 0);
     }
 
     #[test]
-    fn test_CALL() {
-        let data = vec![
-            Opcode::CALL.into(),
-            4,
-            0,
-            Opcode::NOP.into(),
-            Opcode::NOP.into(),
-            Opcode::RET.into(),
-            0,
-            0,
-            0,
-            0,
-        ];
-        let mut cpu = CPU::new(data);
+    fn test_current_immediate() {
+        let mut cpu = CPU::new(vec![0x3E, 0x12, 0xC3, 0x00, 0x01, 0x00]);
+        assert_eq!(cpu.current_immediate8(), Some(0x12));
+        assert_eq!(cpu.current_immediate16(), None);
+        cpu.set_pc(2);
+        assert_eq!(cpu.current_immediate8(), None);
+        assert_eq!(cpu.current_immediate16(), Some(0x0100));
+        cpu.set_pc(5);
+        assert_eq!(cpu.current_immediate8(), None);
+        assert_eq!(cpu.current_immediate16(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_save_memory() {
+        let path = std::env::temp_dir().join(format!("intel8080-{}.bin", std::process::id()));
+        let mut cpu = CPU::new(vec![0; 0x100]);
+        cpu.set_value(0x42, 0x24);
+        cpu.save_memory(&path).unwrap();
+
+        let mut other = CPU::new(vec![0xFF; 0x100]);
+        other.load_memory(&path).unwrap();
+        assert_eq!(other.get_value(0x42), 0x24);
+        assert_eq!(other.get_value(0xFF), 0x00);
+
+        let mut small = CPU::new(vec![0; 0x10]);
+        let err = small.load_memory(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_step_n_with_deltas() {
+        let mut cpu = CPU::new(vec![Opcode::INR_C.into(), Opcode::MVI_A.into(), 0x80, 0x00]);
+        let deltas = cpu.step_n_with_deltas(2);
+        assert_eq!(deltas.len(), 2);
+
+        assert_eq!(deltas[0].step.opcode, Opcode::INR_C);
+        assert_eq!(deltas[0].registers, vec![(Register::C, 0, 1)]);
+        assert_eq!(deltas[0].flags, None);
+        assert_eq!(deltas[0].pc, (0, 1));
+
+        assert_eq!(deltas[1].registers, vec![(Register::Acc, 0, 0x80)]);
+        assert_eq!(deltas[1].pc, (1, 3));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_from_rom_file() {
+        let path = std::env::temp_dir().join(format!("intel8080-rom-{}.bin", std::process::id()));
+        std::fs::write(&path, [Opcode::MVI_A.into(), 0x12, Opcode::HLT.into()]).unwrap();
+
+        let mut cpu = CPU::from_rom_file(&path, 0x200, 0x100).unwrap();
+        assert_eq!(cpu.pc(), 0x100);
+        assert_eq!(cpu.get_value(0x101), 0x12);
+        assert_eq!(cpu.get_value(0x0FF), 0x00);
+        cpu.run_until_halt();
+        assert_eq!(cpu.acc, 0x12);
+
+        let err = CPU::from_rom_file(&path, 0x101, 0x100).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
 
+    #[test]
+    fn test_queue_input() {
+        let code = [
+            0xDB, 0x01, 0x47, 0xDB, 0x01, 0x4F, 0xDB, 0x01, 0x57, 0xDB, 0x01, 0xDB, 0x02,
+        ];
+        let mut cpu = CPU::new(code.to_vec());
+        cpu.queue_input(1, b"ab");
+        cpu.queue_input(1, b"c");
+        cpu.queue_input(2, b"z");
+        for _ in 0..6 {
+            cpu.run_once();
+        }
+        assert_eq!(cpu.registers[..3], *b"abc");
         cpu.run_once();
-        assert_eq!(cpu.pc(), 4);
-
+        assert_eq!(cpu.acc, 0xFF);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 5);
+        assert_eq!(cpu.acc, b'z');
+    }
 
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
+    /// T-states from the 8080 datasheet, with Z clear so `NZ` branches are
+    /// taken and `Z` ones are not.
+    const DATASHEET_CYCLES: [(Opcode, u64); 32] = [
+        (Opcode::NOP, 4),
+        (Opcode::ADD_B, 4),
+        (Opcode::XCHG, 4),
+        (Opcode::EI, 4),
+        (Opcode::MOV_BC, 5),
+        (Opcode::INR_B, 5),
+        (Opcode::INX_B, 5),
+        (Opcode::SPHL, 5),
+        (Opcode::PCHL, 5),
+        (Opcode::RZ, 5),
+        (Opcode::MOV_AM, 7),
+        (Opcode::MVI_A, 7),
+        (Opcode::ADI, 7),
+        (Opcode::LDAX_B, 7),
+        (Opcode::HLT, 7),
+        (Opcode::LXI_B, 10),
+        (Opcode::MVI_M, 10),
+        (Opcode::DAD_B, 10),
+        (Opcode::JMP, 10),
+        (Opcode::JZ, 10),
+        (Opcode::RET, 10),
+        (Opcode::IN, 10),
+        (Opcode::PUSH_B, 11),
+        (Opcode::RST_1, 11),
+        (Opcode::CZ, 11),
+        (Opcode::RNZ, 11),
+        (Opcode::LDA, 13),
+        (Opcode::STA, 13),
+        (Opcode::CALL, 17),
+        (Opcode::CNZ, 17),
+        (Opcode::SHLD, 16),
+        (Opcode::XTHL, 18),
+    ];
 
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 4);
+    #[test]
+    fn test_cycles_per_instruction() {
+        for &(opcode, expected) in DATASHEET_CYCLES.iter() {
+            let mut data = vec![0; 0x10000];
+            data[0x0100] = opcode.into();
+            let mut cpu = CPU::new(data);
+            cpu.set_pc(0x0100);
+            cpu.sp = 0x8000;
+            cpu.run_once();
+            assert_eq!(cpu.cycles(), expected, "{:?}", opcode);
+        }
     }
 
     #[test]
-    fn test_PUSH() {
-        let data = vec![
-            Opcode::PUSH_D.into(),
-            Opcode::PUSH_PSW.into(),
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ];
-        let mut cpu = CPU::new(data);
+    fn test_default() {
+        let cpu = CPU::default();
+        assert_eq!(cpu.memory_size(), 65536);
+        assert_eq!(cpu.pc(), 0);
+        assert_eq!(cpu.registers(), [0; 6]);
+        assert_eq!(cpu.acc, 0);
+        assert_eq!(cpu.flags(), Flag::default());
+    }
 
-        cpu.registers[Register::D as usize] = 0x8f;
-        cpu.registers[Register::E as usize] = 0x9d;
-        cpu.run_once();
+    #[test]
+    fn test_successors() {
+        let mut cpu = CPU::new(vec![
+            0xC3, 0x00, 0x02, 0xCA, 0x05, 0x01, 0x78, 0xC9, 0x34, 0x12,
+        ]);
+        assert_eq!(
+            cpu.successors(),
+            Successors {
+                fall_through: 3,
+                branch_target: Some(0x0200),
+            }
+        );
+        cpu.set_pc(3);
+        assert_eq!(
+            cpu.successors(),
+            Successors {
+                fall_through: 6,
+                branch_target: Some(0x0105),
+            }
+        );
+        cpu.set_pc(6);
+        assert_eq!(
+            cpu.successors(),
+            Successors {
+                fall_through: 7,
+                branch_target: None,
+            }
+        );
+        cpu.set_pc(7);
+        cpu.sp = 8;
+        assert_eq!(cpu.successors().branch_target, Some(0x1234));
+        assert_eq!(cpu.pc(), 7);
+    }
 
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.data[cpu.data.len() - 1], 0x8f);
-        assert_eq!(cpu.data[cpu.data.len() - 2], 0x9d);
+    #[test]
+    fn test_halt_on_write() {
+        let code = assemble(
+            "
+                MVI A, 0x01
+                STA 0x00F0
+                MVI A, 0xAA
+                STA 0x00F0
+                NOP
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x0100];
+        data[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(data);
+        cpu.set_halt_on_write(0x00F0, 0xAA);
 
-        cpu.acc = 0x1f;
-        cpu.flag.set_carry_flag(true);
-        cpu.flag.set_zero_flag(true);
-        cpu.flag.set_parity_flag(true);
-        cpu.flag.set_sign_flag(false);
-        cpu.flag.set_auxiliary_carry_flag(false);
-        cpu.run_once();
+        cpu.run_until_halt();
+        assert!(cpu.halted_by_write());
+        assert_eq!(cpu.pc(), 10);
+        assert_eq!(cpu.get_value(0x00F0), 0xAA);
 
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.data[cpu.data.len() - 3], 0x1f);
-        assert_eq!(cpu.data[cpu.data.len() - 4], 0x47);
+        cpu.reset();
+        assert!(!cpu.halted_by_write());
     }
 
     #[test]
-    fn test_POP() {
-        let data = vec![
-            Opcode::POP_H.into(),
-            Opcode::PUSH_PSW.into(),
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ];
-        let mut cpu = CPU::new(data);
+    fn test_signed_pairs() {
+        let mut cpu = CPU::new(vec![0]);
+        cpu.set_pair(RegisterPair::HL, 0xFFFF);
+        cpu.set_pair(RegisterPair::BC, 0x8000);
+        cpu.set_pair(RegisterPair::DE, 0x7FFF);
+        assert_eq!(cpu.hl_signed(), -1);
+        assert_eq!(cpu.bc_signed(), i16::MIN);
+        assert_eq!(cpu.de_signed(), i16::MAX);
+    }
 
-        cpu.stack_push(0x933d);
-        cpu.run_once();
+    #[test]
+    fn test_opcode_trap() {
+        let code = assemble(
+            "
+                MVI A, 1
+                INR A
+                OUT 2
+                OUT 3
+                HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
+        cpu.set_opcode_trap(Box::new(|opcode| opcode == Opcode::OUT));
+        assert_eq!(cpu.run_until_breakpoint(), Some(3));
+        assert_eq!(cpu.acc, 2);
+
+        cpu.clear_opcode_trap();
+        assert_eq!(cpu.run_until_breakpoint(), None);
+        assert!(cpu.is_halted());
+    }
 
-        assert_eq!(cpu.pc(), 1);
-        assert_eq!(cpu.registers[Register::H as usize], 0x93);
-        assert_eq!(cpu.registers[Register::L as usize], 0x3d);
+    #[test]
+    fn test_memory_crc32() {
+        let mut cpu = CPU::new(b"123456789".to_vec());
+        assert_eq!(cpu.memory_crc32(), 0xCBF4_3926);
+        assert_eq!(CPU::new(Vec::new()).memory_crc32(), 0);
+
+        let state = cpu.state_crc32();
+        assert_ne!(state, cpu.memory_crc32());
+        cpu.set_pc(1);
+        assert_ne!(cpu.state_crc32(), state);
+        assert_eq!(cpu.memory_crc32(), 0xCBF4_3926);
+        cpu.set_pc(0);
+        assert_eq!(cpu.state_crc32(), state);
     }
 
     #[test]
-    fn test_JMP() {
-        // loop
-        let data = vec![Opcode::JMP.into(), 3, 0, Opcode::JMP.into(), 0, 0];
+    fn test_preview_step() {
+        let code = assemble(
+            "
+                MVI A, 0x56
+                PUSH PSW
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x10];
+        data[..code.len()].copy_from_slice(&code);
         let mut cpu = CPU::new(data);
+        let before = cpu.state();
 
+        let (state, writes) = cpu.preview_step();
+        assert_eq!(state.acc, 0x56);
+        assert!(writes.is_empty());
+        assert_eq!(cpu.state(), before);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
-
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 0);
+        assert_eq!(cpu.state(), state);
 
+        let (state, writes) = cpu.preview_step();
+        assert_eq!(writes, vec![(0x0F, 0x56), (0x0E, 0x02)]);
+        assert_eq!(cpu.get_value(0x0F), 0x00);
         cpu.run_once();
-        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.state(), state);
+        assert_eq!(cpu.get_value(0x0F), 0x56);
     }
 
     #[test]
-    fn test_ADI() {
-        let data = vec![Opcode::ADI.into(), 0x42];
+    fn test_call_hook() {
+        let code = assemble(
+            "
+                LXI SP, 0x0100
+                CALL 0x0050
+                CALL 0x0050
+                RST 2
+                HLT
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x0100];
+        data[..code.len()].copy_from_slice(&code);
+        // never reached while hooked
+        data[0x0050] = Opcode::HLT.into();
+        data[0x0010] = Opcode::HLT.into();
         let mut cpu = CPU::new(data);
+        let calls = Rc::new(RefCell::new(0));
+        let counter = calls.clone();
+        cpu.set_call_hook(
+            0x0050,
+            Box::new(move |cpu| {
+                *counter.borrow_mut() += 1;
+                cpu.acc += 1;
+            }),
+        );
+        cpu.set_call_hook(0x0010, Box::new(|cpu| cpu.set_pc(0x0100 - 1)));
 
-        cpu.acc = 0x14;
         cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0x56);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), false);
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 6);
+        assert_eq!(cpu.sp(), 0x0100);
+        cpu.run_once();
+        cpu.run_once();
+        assert_eq!(*calls.borrow(), 2);
+        assert_eq!(cpu.acc, 2);
+        // the RST hook moved PC, so there is no return
+        assert_eq!(cpu.pc(), 0x00FF);
+        assert_eq!(cpu.stack_pop(), 10);
     }
 
     #[test]
-    fn test_SUI() {
-        let data = vec![Opcode::SUI.into(), 1];
-        let mut cpu = CPU::new(data);
+    fn test_set_state_raw() {
+        let mut cpu = CPU::new(vec![0]);
+        cpu.set_state_raw(0x12, 0xD7, 0x3456, 0x789A, 0xBCDE, 0xF000, 0x0100);
+        let state = cpu.state();
+        assert_eq!(state.acc, 0x12);
+        assert_eq!(state.flag, Flag::new(0xD7));
+        assert_eq!(state.registers, [0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE]);
+        assert_eq!((state.sp, state.pc), (0xF000, 0x0100));
+
+        let mut other = CPU::new(vec![0]);
+        let [b, c, d, e, h, l] = state.registers;
+        let pair = |high, low| u16::from(high) << 8 | u16::from(low);
+        other.set_state_raw(
+            state.acc,
+            state.flag.value(),
+            pair(b, c),
+            pair(d, e),
+            pair(h, l),
+            state.sp,
+            state.pc,
+        );
+        assert_eq!(other.state(), state);
+    }
 
-        cpu.acc = 0;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0xFF);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.flag.carry_flag(), true);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
+    #[test]
+    fn test_load_program() {
+        let mut cpu = CPU::new(vec![0x42; 8]);
+        let hlt = Opcode::HLT.into();
+        cpu.load_program(3, &[0x3E, 0x12], hlt).unwrap();
+        assert_eq!(cpu.data, [hlt, hlt, hlt, 0x3E, 0x12, hlt, hlt, hlt]);
+
+        assert!(matches!(
+            cpu.load_program(7, &[0, 0], 0x00),
+            Err(Error::OutOfBounds)
+        ));
+        assert_eq!(cpu.data[0], hlt);
     }
 
     #[test]
-    fn test_ANI() {
-        let data = vec![Opcode::ANI.into(), 0x0f];
+    fn test_smc_detection() {
+        let code = assemble(
+            "
+                MVI A, 0x3C
+                STA 0x0080
+                STA 0x0001
+                HLT
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x0100];
+        data[..code.len()].copy_from_slice(&code);
         let mut cpu = CPU::new(data);
+        cpu.enable_smc_detection(0x0000, code.len() as u16 - 1);
 
-        cpu.acc = 0x3a;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0x0a);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.zero_flag(), false);
+        cpu.run_until_halt();
+        assert_eq!(cpu.take_smc_events(), vec![0x0001]);
+        assert!(cpu.take_smc_events().is_empty());
     }
 
     #[test]
-    fn test_ORI() {
-        let data = vec![Opcode::ORI.into(), 0x0f];
-        let mut cpu = CPU::new(data);
+    fn test_step_record_totals() {
+        let mut cpu = CPU::new(vec![0x3E, 0x12, 0x00, 0xC3, 0x00, 0x00]);
+        let records = (0..6).map(|_| cpu.step_debug()).collect::<Vec<_>>();
+        for pair in records.windows(2) {
+            assert_eq!(pair[1].total_cycles, pair[0].total_cycles + pair[1].cycles);
+            assert_eq!(pair[1].total_instructions, pair[0].total_instructions + 1);
+        }
+        let last = records.last().unwrap();
+        assert_eq!(last.total_cycles, cpu.cycles());
+        assert_eq!(last.total_instructions, 6);
+    }
 
-        cpu.acc = 0xb5;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0xbf);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.zero_flag(), false);
+    #[test]
+    fn test_inject_instruction() {
+        let mut cpu = CPU::new(vec![0; 0x100]);
+        cpu.set_pc(0x40);
+        cpu.inject_instruction(0xC7).unwrap();
+        assert_eq!(cpu.pc(), 0);
+        assert_eq!(cpu.stack_pop(), 0x40);
+        assert_eq!(cpu.cycles(), 11);
+        assert!(!cpu.state().interrupts_enabled);
+
+        // ignored while interrupts are disabled
+        cpu.set_pc(0x40);
+        cpu.inject_instruction(0xCF).unwrap();
+        assert_eq!(cpu.pc(), 0x40);
+
+        // any one-byte instruction, which leaves PC alone
+        cpu.execute(Opcode::EI);
+        cpu.set_pc(0x40);
+        cpu.inject_instruction(Opcode::INR_A.into()).unwrap();
+        assert_eq!((cpu.pc(), cpu.acc), (0x40, 1));
+
+        assert!(matches!(
+            cpu.inject_instruction(0xC3),
+            Err(Error::IllegalValue)
+        ));
+        assert!(matches!(
+            cpu.inject_instruction(0xCB),
+            Err(Error::IllegalValue)
+        ));
     }
 
     #[test]
-    fn test_ACI() {
-        let data = vec![Opcode::ACI.into(), 0x42];
-        let mut cpu = CPU::new(data);
+    fn test_step_hook() {
+        let mut cpu = CPU::new(vec![0x00, 0x3E, 0x12, 0x76]);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        cpu.set_step_hook(Box::new(move |cpu| {
+            log.borrow_mut().push((cpu.pc(), cpu.acc))
+        }));
+        cpu.run_until_halt();
+        cpu.run_once();
+        assert_eq!(*seen.borrow(), vec![(0, 0), (1, 0), (3, 0x12)]);
 
-        cpu.flag.set_carry_flag(true);
-        cpu.acc = 0x14;
+        cpu.clear_step_hook();
+        cpu.reset();
         cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0x57);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
+        assert_eq!(seen.borrow().len(), 3);
     }
 
     #[test]
-    fn test_SBI() {
-        let data = vec![Opcode::SBI.into(), 0x1];
-        let mut cpu = CPU::new(data);
+    fn test_uninitialized_read() {
+        let code = assemble(
+            "
+                LXI H, 0x0080
+                MVI M, 1
+                MOV A, M
+                LDA 0x0081
+                HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(vec![0; 0x0100]);
+        cpu.enable_uninitialized_read_detection();
+        cpu.load_at(0, &code).unwrap();
 
-        cpu.flag.set_carry_flag(true);
-        cpu.acc = 0;
+        for _ in 0..3 {
+            cpu.run_once_checked().unwrap();
+        }
+        assert!(matches!(
+            cpu.run_once_checked(),
+            Err(Error::UninitializedRead { addr: 0x0081 })
+        ));
+        assert_eq!(cpu.pc(), 9);
+        assert_eq!(cpu.run_once_checked().unwrap(), Opcode::HLT);
+
+        cpu.reset();
         cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0xFE);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.flag.carry_flag(), true);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        // todo: check
-        assert_eq!(cpu.flag.auxiliary_flag(), true);
+        cpu.run_once();
+        cpu.run_once();
+        assert_eq!(cpu.tick(), Tick::UninitializedRead(0x0081));
     }
 
     #[test]
-    fn test_XRI() {
-        let data = vec![Opcode::XRI.into(), 0x81];
-        let mut cpu = CPU::new(data);
+    fn test_clear_control_state() {
+        let mut cpu = CPU::new(vec![
+            Opcode::MVI_A.into(),
+            0x42,
+            Opcode::DI.into(),
+            Opcode::HLT.into(),
+        ]);
+        cpu.run_until_halt();
+        cpu.request_interrupt(1);
+        assert!(cpu.is_halted());
+
+        cpu.clear_control_state();
+        assert!(!cpu.is_halted());
+        assert!(!cpu.has_pending_interrupt());
+        assert!(cpu.state().interrupts_enabled);
+        assert_eq!(cpu.acc, 0x42);
+        assert_eq!(cpu.pc(), 4);
+        assert_eq!(cpu.instruction_count(), 3);
+    }
 
-        cpu.acc = 0x3b;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0xBA);
-        assert_eq!(cpu.flag.parity_flag(), false);
-        assert_eq!(cpu.flag.sign_flag(), true);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.zero_flag(), false);
+    #[test]
+    fn test_add16_sub16() {
+        let mut cpu = CPU::new(vec![0]);
+        cpu.set_flags(Flag::new(0b0100_0110));
+        cpu.set_pair(RegisterPair::DE, 0xFFF0);
+        cpu.add16(RegisterPair::DE, 0x0020);
+        assert_eq!(cpu.pair(RegisterPair::DE), 0x0010);
+        assert_eq!(cpu.flags().value(), 0b0100_0111);
+
+        cpu.add16(RegisterPair::DE, 0x0001);
+        assert_eq!(cpu.pair(RegisterPair::DE), 0x0011);
+        assert!(!cpu.flags().carry_flag());
+
+        cpu.sub16(RegisterPair::DE, 0x0012);
+        assert_eq!(cpu.pair(RegisterPair::DE), 0xFFFF);
+        assert_eq!(cpu.flags().value(), 0b0100_0111);
+        cpu.sub16(RegisterPair::SP, 0);
+        assert!(!cpu.flags().carry_flag());
     }
 
     #[test]
-    fn test_CPI() {
-        let data = vec![Opcode::CPI.into(), 0x40];
-        let mut cpu = CPU::new(data);
+    fn test_breakpoint_at_pc() {
+        let code = assemble(
+            "
+                    MVI B, 3
+            loop:   DCR B
+                    JNZ loop
+                    HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
+        cpu.add_breakpoint(2);
+        assert_eq!(cpu.run_until_breakpoint(), Some(2));
+        assert_eq!(cpu.registers[Register::B as usize], 3);
+
+        // resuming runs DCR B before the breakpoint can trigger again
+        assert_eq!(cpu.run_until_breakpoint(), Some(2));
+        assert_eq!(cpu.registers[Register::B as usize], 2);
+        assert_eq!(cpu.instruction_count(), 3);
+
+        cpu.set_pc(6);
+        cpu.add_breakpoint(6);
+        assert_eq!(cpu.run_until_breakpoint(), None);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.run_until_breakpoint(), None);
+    }
 
-        cpu.acc = 0x4a;
-        cpu.run_once();
-        assert_eq!(cpu.pc(), 2);
-        assert_eq!(cpu.acc, 0x4a);
-        assert_eq!(cpu.flag.parity_flag(), true);
-        assert_eq!(cpu.flag.sign_flag(), false);
-        assert_eq!(cpu.flag.carry_flag(), false);
-        assert_eq!(cpu.flag.zero_flag(), false);
-        assert_eq!(cpu.flag.auxiliary_flag(), false);
+    #[test]
+    fn test_execute_byte_daa_any_acc() {
+        for &flags in [true, false].iter() {
+            for acc in 0..=255u8 {
+                let mut cpu = CPU::new(vec![0; 3]);
+                cpu.acc = acc;
+                cpu.flag.set_auxiliary_carry_flag(flags);
+                cpu.flag.set_carry_flag(flags);
+                assert!(cpu.execute_byte(0x27).is_ok());
+                assert_eq!(cpu.pc(), 1);
+            }
+        }
     }
 
     #[test]
-    fn test_SPHL() {
-        let data = vec![Opcode::SPHL.into()];
-        let mut cpu = CPU::new(data);
+    fn test_event_interrupt_checked() {
+        let mut cpu = CPU::new(vec![0; 0x10]);
+        cpu.sp = 0x10;
+        cpu.schedule(0, Box::new(|cpu| cpu.request_interrupt(1)));
+        assert_eq!(cpu.tick(), Tick::Running);
+        assert_eq!(cpu.pc(), 0x08);
+        assert_eq!(cpu.sp(), 0x0E);
+        assert_eq!(cpu.interrupts_serviced(), 1);
+
+        let mut cpu = CPU::new(vec![0; 0x10]);
+        cpu.sp = 0x10;
+        cpu.schedule(0, Box::new(|cpu| cpu.request_interrupt(1)));
+        let record = cpu.step_debug();
+        assert_eq!(record.pc, 0);
+        assert_eq!(record.opcode, Opcode::RST_1);
+        assert_eq!(record.branch_taken, None);
+        assert_eq!(cpu.pc(), 0x08);
+    }
 
-        cpu.registers[Register::H as usize] = 0x50;
-        cpu.registers[Register::L as usize] = 0x6c;
-        cpu.run_once();
-        assert_eq!(cpu.sp(), 0x506c);
+    #[test]
+    fn test_step_record_long_step() {
+        let mut cpu = CPU::new(vec![Opcode::MOV_AM.into(), Opcode::NOP.into()]);
+        cpu.set_wait_state_hook(Box::new(|_, kind| match kind {
+            AccessKind::Read => 300,
+            _ => 0,
+        }));
+        let record = cpu.step_debug();
+        assert_eq!(record.cycles, 7 + 300);
+        assert_eq!(record.total_cycles, 307);
+        assert_eq!(cpu.step_debug().cycles, 4);
     }
 
     #[test]
-    fn test_XCHG() {
-        let data = vec![Opcode::XCHG.into()];
-        let mut cpu = CPU::new(data);
+    fn test_rewind_interrupt_tick() {
+        let mut cpu = CPU::new(vec![0; 0x10]);
+        cpu.load_at(0, &[Opcode::MVI_A.into(), 1]).unwrap();
+        cpu.sp = 0x10;
+        cpu.enable_rewind(8);
+        assert_eq!(cpu.tick(), Tick::Running);
+        let before = cpu.state();
+
+        cpu.request_interrupt(1);
+        assert_eq!(cpu.tick(), Tick::Running);
+        assert_eq!(cpu.pc(), 0x08);
+        assert_eq!(cpu.get_value(0x0E), 0x02);
+
+        cpu.rewind(1).unwrap();
+        assert_eq!(cpu.state(), before);
+        assert_eq!(cpu.get_value(0x0E), 0);
+        cpu.rewind(1).unwrap();
+        assert_eq!(cpu.pc(), 0);
+        assert_eq!(cpu.acc, 0);
+    }
 
-        cpu.registers[Register::H as usize] = 0x00;
-        cpu.registers[Register::L as usize] = 0xFF;
-        cpu.registers[Register::D as usize] = 0x33;
-        cpu.registers[Register::E as usize] = 0x55;
+    #[test]
+    fn test_rewind_injected_rst() {
+        let mut cpu = CPU::new(vec![0; 0x10]);
+        cpu.sp = 0x10;
+        cpu.set_pc(0x04);
+        cpu.enable_rewind(4);
+        cpu.enable_mem_trace();
+        let before = cpu.state();
+
+        cpu.inject_instruction(0xC7).unwrap();
+        assert_eq!(cpu.pc(), 0);
+        assert_eq!(
+            cpu.take_mem_trace()
+                .iter()
+                .map(|access| (access.addr, access.value, access.kind))
+                .collect::<Vec<_>>(),
+            vec![
+                (0x0F, 0x00, AccessKind::Write),
+                (0x0E, 0x04, AccessKind::Write)
+            ]
+        );
 
-        cpu.run_once();
+        cpu.rewind(1).unwrap();
+        assert_eq!(cpu.state(), before);
+        assert_eq!(cpu.get_value(0x0E), 0);
+        assert_eq!(cpu.interrupts_serviced(), 0);
+    }
 
-        assert_eq!(cpu.registers[Register::H as usize], 0x33);
-        assert_eq!(cpu.registers[Register::L as usize], 0x55);
-        assert_eq!(cpu.registers[Register::D as usize], 0x00);
-        assert_eq!(cpu.registers[Register::E as usize], 0xFF);
+    #[test]
+    fn test_dispatch_table() {
+        for n in 0..=255u8 {
+            let mut cpu = CPU::new((0..=255u8).rev().collect());
+            cpu.set_state_raw(0x5A, 0b1000_0011, 0x1234, 0x80F0, 0x0042, 0x00F0, 0x10);
+            cpu.set_value(0x10, n);
+            let mut expected = cpu.clone();
+
+            let cycles = DISPATCH[usize::from(n)](&mut cpu);
+            assert_eq!(
+                cycles,
+                expected.execute_injected(Opcode::from(n)),
+                "{:02X}",
+                n
+            );
+            assert!(cpu.architectural_eq(&expected), "{:02X}", n);
+        }
     }
 
     #[test]
-    fn test_XTHL() {
-        let data = vec![Opcode::XTHL.into(), 0, 1, 2, 3];
+    fn test_with_memory() {
+        use crate::memory::Region;
+
+        let code = assemble(
+            "
+                    LXI SP, 0x3000
+                    MVI A, 0x42
+                    STA 0x2010
+                    LDA 0x4010
+                    MOV B, A
+                    STA 0x0001
+                    MVI A, 0x24
+                    STA 0x5FFF
+                    LDA 0x8000
+                    MOV C, A
+                    CALL sub
+                    HLT
+            sub:    RET
+            ",
+        )
+        .unwrap();
+        let rom = code.clone();
+        let mut memory = Memory::new();
+        memory.map(0x0000..=0x1FFF, Region::Rom(code));
+        memory.map(0x2000..=0x3FFF, Region::Ram);
+        memory.map(0x4000..=0x5FFF, Region::Mirror(0x2000));
+        let mut cpu = CPU::with_memory(memory);
+        cpu.run_until_halt();
+
+        assert_eq!(cpu.registers[Register::B as usize], 0x42);
+        assert_eq!(cpu.get_value(0x0001), rom[1]);
+        assert_eq!(cpu.get_value(0x3FFF), 0x24);
+        assert_eq!(cpu.registers[Register::C as usize], 0xFF);
+        assert_eq!(cpu.get_value(0x4FFE), cpu.get_value(0x2FFE));
+        assert_eq!(cpu.get_value(0x4FFF), cpu.get_value(0x2FFF));
+        assert_ne!(cpu.get_value(0x4FFE), 0);
+    }
+
+    #[test]
+    fn test_run_until_breakpoint_wakes_on_interrupt() {
+        let mut data = assemble(
+            "
+                    EI
+                    HLT
+                    HLT
+            ",
+        )
+        .unwrap();
+        data.resize(0x10, 0);
+        data[0x08] = Opcode::MVI_B.into();
+        data[0x09] = 0x42;
+        data[0x0A] = Opcode::HLT.into();
         let mut cpu = CPU::new(data);
-        cpu.stack_push(0x0DF0);
+        cpu.add_breakpoint(0x0A);
+        assert_eq!(cpu.run_until_breakpoint(), None);
+        assert!(cpu.is_halted());
+
+        cpu.request_interrupt(1);
+        assert_eq!(cpu.run_until_breakpoint(), Some(0x0A));
+        assert_eq!(cpu.registers[Register::B as usize], 0x42);
+        assert_eq!(cpu.run_until_breakpoint(), None);
+    }
 
-        cpu.registers[Register::H as usize] = 0x0B;
-        cpu.registers[Register::L as usize] = 0x3C;
-        cpu.run_once();
+    #[test]
+    fn test_preview_step_skips_call_hook() {
+        let code = assemble(
+            "
+                    CALL 0x0008
+                    HLT
+            ",
+        )
+        .unwrap();
+        let mut data = vec![0; 0x10];
+        data[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(data);
+        cpu.set_call_hook(0x0008, Box::new(|cpu| cpu.acc = 0x42));
 
-        assert_eq!(cpu.registers[Register::H as usize], 0x0D);
-        assert_eq!(cpu.registers[Register::L as usize], 0xF0);
-        assert_eq!(cpu.data[cpu.data.len() - 1], 0x0B);
-        assert_eq!(cpu.data[cpu.data.len() - 2], 0x3C);
+        let (state, writes) = cpu.preview_step();
+        assert_eq!(state.pc, 0x0008);
+        assert_eq!(state.acc, 0);
+        assert_eq!(writes, vec![(0x0F, 0x00), (0x0E, 0x03)]);
+
+        cpu.run_once();
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!(cpu.acc, 0x42);
     }
 }
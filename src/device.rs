@@ -0,0 +1,359 @@
+//! Ready-made `Device`s for `CPU::set_device` and `CPU::with_devices`.
+
+use crate::cpu::Device;
+use crate::memory::MemoryBus;
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Feeds bytes from a reader, such as stdin or a script, to `IN` on one port.
+///
+/// It only answers `IN` on that port, so other ports read the open-bus value
+/// on a CPU, or 0 when the device is used directly. `OUT` is ignored.
+pub struct ConsoleInput<R: Read> {
+    port: u8,
+    source: Source<R>,
+}
+
+enum Source<R> {
+    Reader(R),
+    Channel(Receiver<u8>),
+}
+
+impl<R: Read> ConsoleInput<R> {
+    /// Each `IN` waits for the next byte from `reader`, reading 0 once it is exhausted.
+    pub fn blocking(reader: R, port: u8) -> Self {
+        Self {
+            port,
+            source: Source::Reader(reader),
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        match self.source {
+            Source::Reader(ref mut reader) => {
+                let mut buf = [0];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(1) => return buf[0],
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        _ => return 0,
+                    }
+                }
+            }
+            Source::Channel(ref bytes) => bytes.try_recv().unwrap_or(0),
+        }
+    }
+}
+
+impl<R: Read + Send + 'static> ConsoleInput<R> {
+    /// `IN` never waits: it reads 0 until `reader` has delivered another byte.
+    ///
+    /// `reader` is drained on a background thread.
+    pub fn non_blocking(mut reader: R, port: u8) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0];
+            while let Ok(1) = reader.read(&mut buf) {
+                if tx.send(buf[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            port,
+            source: Source::Channel(rx),
+        }
+    }
+}
+
+impl<R: Read> Device for ConsoleInput<R> {
+    fn input(&mut self, port: u8) -> u8 {
+        if port == self.port {
+            self.next_byte()
+        } else {
+            0
+        }
+    }
+
+    fn output(&mut self, _port: u8, _data: u8) {}
+
+    fn is_mapped(&self, port: u8) -> bool {
+        port == self.port
+    }
+}
+
+/// Routes each port to its own device. Unmapped ports ignore writes; on a
+/// CPU they read its open-bus value, and 0xFF when the bus is used directly.
+///
+/// To serve several ports with one device, attach clones of an
+/// `Rc<RefCell<_>>` holding it.
+pub struct IoBus {
+    ports: Vec<Option<Box<dyn Device>>>,
+}
+
+impl Default for IoBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoBus {
+    /// A bus with nothing attached.
+    pub fn new() -> Self {
+        Self {
+            ports: (0..256).map(|_| None).collect(),
+        }
+    }
+
+    /// Connects `device` to `port`, replacing whatever was there.
+    pub fn attach(&mut self, port: u8, device: Box<dyn Device>) {
+        self.ports[usize::from(port)] = Some(device);
+    }
+}
+
+impl Device for IoBus {
+    fn input(&mut self, port: u8) -> u8 {
+        match self.ports[usize::from(port)] {
+            Some(ref mut device) => device.input(port),
+            None => 0xFF,
+        }
+    }
+
+    fn output(&mut self, port: u8, data: u8) {
+        if let Some(ref mut device) = self.ports[usize::from(port)] {
+            device.output(port, data);
+        }
+    }
+
+    fn is_mapped(&self, port: u8) -> bool {
+        match self.ports[usize::from(port)] {
+            Some(ref device) => device.is_mapped(port),
+            None => false,
+        }
+    }
+}
+
+/// The external shift register of Space Invaders, which the 8080 lacks
+/// a barrel shifter for.
+///
+/// `OUT 4` shifts a byte in from the top of a 16-bit register, `OUT 2` sets
+/// the shift amount (0-7) and `IN 3` reads the 8 bits that many bits below
+/// the top. Attach it to ports 2, 3 and 4.
+#[derive(Debug, Default)]
+pub struct ShiftRegister {
+    value: u16,
+    offset: u8,
+}
+
+impl Device for ShiftRegister {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            3 => (self.value >> (8 - self.offset)) as u8,
+            _ => 0xFF,
+        }
+    }
+
+    fn output(&mut self, port: u8, data: u8) {
+        match port {
+            2 => self.offset = data & 0b111,
+            4 => self.value = u16::from(data) << 8 | self.value >> 8,
+            _ => {}
+        }
+    }
+
+    fn is_mapped(&self, port: u8) -> bool {
+        port == 3
+    }
+}
+
+/// A block-copy DMA controller programmed through seven ports from `base`.
+///
+/// `OUT base` and `base + 1` set the low and high byte of the source address,
+/// `base + 2` and `base + 3` the destination and `base + 4` and `base + 5` the
+/// byte count. `OUT base + 6` starts the transfer and `IN base + 6` reads 1
+/// until it has run.
+///
+/// The device can't reach memory from `OUT`, so the transfer happens when the
+/// host next calls `transfer`, e.g. from `CPU::run_with`.
+#[derive(Debug)]
+pub struct DmaController {
+    base: u8,
+    src: u16,
+    dst: u16,
+    count: u16,
+    pending: bool,
+}
+
+impl DmaController {
+    /// T-states the CPU loses for each byte copied.
+    pub const CYCLES_PER_BYTE: u64 = 4;
+
+    pub fn new(base: u8) -> Self {
+        Self {
+            base,
+            src: 0,
+            dst: 0,
+            count: 0,
+            pending: false,
+        }
+    }
+
+    /// Runs a started transfer over `bus`, one byte at a time from the lowest
+    /// address, and returns the cycles to pass to `CPU::steal_cycles`. Returns
+    /// 0 when nothing was started.
+    pub fn transfer(&mut self, bus: &mut dyn MemoryBus) -> u64 {
+        if !self.pending {
+            return 0;
+        }
+        self.pending = false;
+        for i in 0..self.count {
+            let val = bus.read(self.src.wrapping_add(i));
+            bus.write(self.dst.wrapping_add(i), val);
+        }
+        u64::from(self.count) * Self::CYCLES_PER_BYTE
+    }
+}
+
+impl Device for DmaController {
+    fn input(&mut self, port: u8) -> u8 {
+        match port.wrapping_sub(self.base) {
+            6 => self.pending as u8,
+            _ => 0xFF,
+        }
+    }
+
+    fn output(&mut self, port: u8, data: u8) {
+        let set_low = |word: &mut u16| *word = *word & 0xFF00 | u16::from(data);
+        let set_high = |word: &mut u16| *word = *word & 0x00FF | u16::from(data) << 8;
+        match port.wrapping_sub(self.base) {
+            0 => set_low(&mut self.src),
+            1 => set_high(&mut self.src),
+            2 => set_low(&mut self.dst),
+            3 => set_high(&mut self.dst),
+            4 => set_low(&mut self.count),
+            5 => set_high(&mut self.count),
+            6 => self.pending = true,
+            _ => {}
+        }
+    }
+
+    fn is_mapped(&self, port: u8) -> bool {
+        port.wrapping_sub(self.base) == 6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::register::Register;
+    use std::cell::RefCell;
+    use std::ops::ControlFlow;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_console_input() {
+        let code = assemble(
+            "
+            IN 1
+            MOV B, A
+            IN 2
+            MOV C, A
+            IN 1
+            MOV D, A
+            IN 1
+            HLT
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(code);
+        cpu.set_device(Box::new(ConsoleInput::blocking(&b"hi"[..], 1)));
+        cpu.set_open_bus(0x7E);
+        cpu.acc = 0xFF;
+
+        while !cpu.is_halted() {
+            cpu.run_once();
+        }
+        assert_eq!(cpu.registers[Register::B as usize], b'h');
+        assert_eq!(cpu.registers[Register::C as usize], 0x7E);
+        assert_eq!(cpu.registers[Register::D as usize], b'i');
+        assert_eq!(cpu.acc, 0);
+    }
+
+    #[test]
+    fn test_io_bus_shift_register() {
+        let code = assemble(
+            "
+            MVI A, 0xAB
+            OUT 4
+            MVI A, 0xCD
+            OUT 4
+            MVI A, 4
+            OUT 2
+            IN 3
+            MOV B, A
+            OUT 7
+            IN 7
+            HLT
+            ",
+        )
+        .unwrap();
+        let shifter = Rc::new(RefCell::new(ShiftRegister::default()));
+        let mut bus = IoBus::new();
+        for &port in [2, 3, 4].iter() {
+            bus.attach(port, Box::new(shifter.clone()));
+        }
+        let mut cpu = CPU::with_devices(code, bus);
+
+        cpu.run_until_halt();
+        assert_eq!(cpu.registers[Register::B as usize], 0xDA);
+        assert_eq!(cpu.acc, 0xFF);
+        assert_eq!(shifter.borrow().value, 0xCDAB);
+    }
+
+    #[test]
+    fn test_dma_controller() {
+        let code = assemble(
+            "
+                    MVI A, 0x00
+                    OUT 0x10
+                    MVI A, 0x10
+                    OUT 0x11
+                    MVI A, 0x00
+                    OUT 0x12
+                    MVI A, 0x20
+                    OUT 0x13
+                    MVI A, 0x00
+                    OUT 0x14
+                    MVI A, 0x01
+                    OUT 0x15
+                    OUT 0x16
+            wait:   IN 0x16
+                    ORA A
+                    JNZ wait
+                    HLT
+            ",
+        )
+        .unwrap();
+        let source: Vec<u8> = (0..=255u8).map(|i| i ^ 0x5A).collect();
+        let mut cpu = CPU::default();
+        cpu.load_at(0, &code).unwrap();
+        cpu.load_at(0x1000, &source).unwrap();
+        let dma = Rc::new(RefCell::new(DmaController::new(0x10)));
+        cpu.set_device(Box::new(dma.clone()));
+
+        cpu.run_with(|cpu| {
+            let stolen = dma.borrow_mut().transfer(cpu);
+            cpu.steal_cycles(stolen);
+            ControlFlow::Continue(())
+        });
+        let copied: Vec<u8> = (0x2000..0x2100).map(|addr| cpu.get_value(addr)).collect();
+        assert_eq!(copied, source);
+        assert_eq!(cpu.get_value(0x2100), 0);
+        // 6 MVI/OUT pairs, OUT, one pass of the wait loop and HLT
+        assert_eq!(cpu.cycles(), 6 * 17 + 10 + 24 + 7 + 256 * 4);
+        assert_eq!(dma.borrow_mut().transfer(&mut cpu), 0);
+    }
+}
@@ -0,0 +1,259 @@
+//! Turns machine code back into assembler text, e.g. `MVI A,$12` or `JMP $0100`.
+
+use crate::opcode::Opcode;
+use crate::register::Register;
+use std::collections::HashMap;
+
+const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+
+/// Disassembles the instruction at `addr`, returning its text and length in bytes.
+///
+/// An instruction cut short by the end of `data` comes out as its bytes in a
+/// `DB` line, e.g. `DB $C3,$00`, with the length of what is there.
+///
+/// Panics if `addr` is past the end of `data`, since there is no instruction
+/// there and a length of 0 would stall a caller stepping by it.
+pub fn disassemble(data: &[u8], addr: usize) -> (String, usize) {
+    disassemble_with_symbols(data, addr, &HashMap::new())
+}
+
+/// Like `disassemble`, but 16-bit operands that match an address in `symbols`
+/// are printed as the symbol name, e.g. `JMP loop` instead of `JMP $0100`.
+///
+/// The assembler returns its symbol table as name → address, so invert it first.
+pub fn disassemble_with_symbols(
+    data: &[u8],
+    addr: usize,
+    symbols: &HashMap<u16, String>,
+) -> (String, usize) {
+    let opcode = match data.get(addr) {
+        Some(&byte) => Opcode::from(byte),
+        None => panic!(
+            "address {:#06X} is past the end of {} bytes",
+            addr,
+            data.len()
+        ),
+    };
+    let len = opcode.length();
+    if addr + len > data.len() {
+        let bytes = data[addr..]
+            .iter()
+            .map(|b| format!("${:02X}", b))
+            .collect::<Vec<_>>();
+        return (format!("DB {}", bytes.join(",")), data.len() - addr);
+    }
+    let dst = Register::from(opcode.get_dest_num() as usize).name();
+    let src = Register::from(opcode.get_src_num() as usize).name();
+    let pair = PAIRS[opcode.get_rp_num_2() as usize];
+    let d8 = || format!("${:02X}", data[addr + 1]);
+    let a16 = || {
+        let val = u16::from(data[addr + 1]) | u16::from(data[addr + 2]) << 8;
+        match symbols.get(&val) {
+            Some(name) => name.clone(),
+            None => format!("${:04X}", val),
+        }
+    };
+
+    let mnemonic = opcode.mnemonic();
+    let operands = match mnemonic {
+        "MOV" => format!("{},{}", dst, src),
+        "MVI" => format!("{},{}", dst, d8()),
+        "INR" | "DCR" => dst.to_string(),
+        "ADD" | "ADC" | "SUB" | "SBB" | "ANA" | "XRA" | "ORA" | "CMP" => src.to_string(),
+        "LXI" => format!("{},{}", pair, a16()),
+        "DAD" | "INX" | "DCX" | "STAX" | "LDAX" => pair.to_string(),
+        "PUSH" | "POP" if opcode.get_rp_num_2() == 0b11 => "PSW".to_string(),
+        "PUSH" | "POP" => pair.to_string(),
+        "RST" => opcode.get_dest_num().to_string(),
+        _ if len == 2 => d8(),
+        _ if len == 3 => a16(),
+        _ => String::new(),
+    };
+
+    if operands.is_empty() {
+        (mnemonic.to_string(), len)
+    } else {
+        (format!("{} {}", mnemonic, operands), len)
+    }
+}
+
+/// Disassembles every instruction starting in `start..end`, paired with its address.
+/// The range is cut off at the end of `data`.
+pub fn disassemble_range(
+    data: &[u8],
+    start: usize,
+    end: usize,
+    symbols: &HashMap<u16, String>,
+) -> Vec<(u16, String)> {
+    let mut listing = Vec::new();
+    let end = end.min(data.len());
+    let mut addr = start;
+    while addr < end {
+        let (text, len) = disassemble_with_symbols(data, addr, symbols);
+        listing.push((addr as u16, text));
+        addr += len;
+    }
+    listing
+}
+
+/// A listing of every instruction starting in `start..end`, one per line with
+/// its address and bytes, e.g. `0100  C3 00 01  JMP $0100`.
+pub fn disassemble_listing(data: &[u8], start: usize, end: usize) -> String {
+    let mut listing = String::new();
+    for (addr, opcode, text) in Disassembler::new(data, start) {
+        let addr = usize::from(addr);
+        if addr >= end {
+            break;
+        }
+        let len = opcode.length().min(data.len() - addr);
+        let bytes = data[addr..addr + len]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        listing.push_str(&format!("{:04X}  {:<8}  {}\n", addr, bytes, text));
+    }
+    listing
+}
+
+/// Disassembles one instruction after another, yielding each one's address,
+/// opcode and text.
+///
+/// Stops at the end of `data`. If the last instruction is cut short, its
+/// bytes are yielded as a `DB` line, e.g. `DB $C3,$00`.
+pub struct Disassembler<'a> {
+    data: &'a [u8],
+    pc: usize,
+}
+
+impl<'a> Disassembler<'a> {
+    /// Starts disassembling `data` at `pc`.
+    pub fn new(data: &'a [u8], pc: usize) -> Self {
+        Self { data, pc }
+    }
+}
+
+impl<'a> Iterator for Disassembler<'a> {
+    type Item = (u16, Opcode, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let addr = self.pc;
+        let opcode = Opcode::from(*self.data.get(addr)?);
+        let (text, len) = disassemble(self.data, addr);
+        self.pc += len;
+        Some((addr as u16, opcode, text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_symbols;
+
+    #[test]
+    fn test_disassemble() {
+        let data = [0x3E, 0x12, 0xC3, 0x00, 0x01, 0x70, 0xF5, 0x29, 0xDF];
+
+        assert_eq!(disassemble(&data, 0), ("MVI A,$12".to_string(), 2));
+        assert_eq!(disassemble(&data, 2), ("JMP $0100".to_string(), 3));
+        assert_eq!(disassemble(&data, 5), ("MOV M,B".to_string(), 1));
+        assert_eq!(disassemble(&data, 6), ("PUSH PSW".to_string(), 1));
+        assert_eq!(disassemble(&data, 7), ("DAD H".to_string(), 1));
+        assert_eq!(disassemble(&data, 8), ("RST 3".to_string(), 1));
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let source = "
+                ORG 0x0100
+        start:  LXI H, table
+        loop:   DCR B
+                JNZ loop
+                JMP start
+        table:  DB 0
+        ";
+        let (code, symbols) = assemble_with_symbols(source).unwrap();
+        let symbols = symbols
+            .into_iter()
+            .map(|(name, addr)| (addr, name))
+            .collect::<HashMap<_, _>>();
+        let mut data = vec![0; 0x0100];
+        data.extend_from_slice(&code);
+
+        let listing = disassemble_range(&data, 0x0100, 0x010B, &symbols);
+        assert_eq!(
+            listing,
+            vec![
+                (0x0100, "LXI H,table".to_string()),
+                (0x0103, "DCR B".to_string()),
+                (0x0104, "JNZ loop".to_string()),
+                (0x0107, "JMP start".to_string()),
+                (0x010A, "NOP".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassembler() {
+        let data = [0x3E, 0x12, 0x00, 0x76, 0xC3, 0x00];
+        let listing = Disassembler::new(&data, 0).collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            vec![
+                (0, Opcode::MVI_A, "MVI A,$12".to_string()),
+                (2, Opcode::NOP, "NOP".to_string()),
+                (3, Opcode::HLT, "HLT".to_string()),
+                (4, Opcode::JMP, "DB $C3,$00".to_string()),
+            ]
+        );
+        assert_eq!(Disassembler::new(&data, 6).next(), None);
+    }
+
+    #[test]
+    fn test_disassemble_listing() {
+        let mut data = vec![0; 0x0100];
+        data.extend_from_slice(&[0xC3, 0x00, 0x01, 0x3E, 0x12, 0x76, 0xCD, 0x00]);
+        assert_eq!(
+            disassemble_listing(&data, 0x0100, data.len()),
+            "0100  C3 00 01  JMP $0100\n\
+             0103  3E 12     MVI A,$12\n\
+             0105  76        HLT\n\
+             0106  CD 00     DB $CD,$00\n"
+        );
+        assert_eq!(
+            disassemble_listing(&data, 0x0100, 0x0104),
+            "0100  C3 00 01  JMP $0100\n0103  3E 12     MVI A,$12\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        let data = [0x00, 0x00, 0xC3];
+        assert_eq!(disassemble(&data, 2), ("DB $C3".to_string(), 1));
+        assert_eq!(disassemble(&[0x3E], 0), ("DB $3E".to_string(), 1));
+        assert_eq!(
+            disassemble_range(&data, 0, 3, &HashMap::new()),
+            vec![
+                (0, "NOP".to_string()),
+                (1, "NOP".to_string()),
+                (2, "DB $C3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "address 0x0005 is past the end of 1 bytes")]
+    fn test_disassemble_past_end() {
+        disassemble(&[0], 5);
+    }
+
+    #[test]
+    fn test_disassemble_range_past_end() {
+        let symbols = HashMap::new();
+        assert_eq!(
+            disassemble_range(&[0x00, 0x3E], 0, 0x10, &symbols),
+            vec![(0, "NOP".to_string()), (1, "DB $3E".to_string())]
+        );
+        assert!(disassemble_range(&[0x00], 4, 8, &symbols).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use std::io;
 use std::result;
 
 pub type Result<T> = result::Result<T, Error>;
@@ -5,4 +6,32 @@ pub type Result<T> = result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     IllegalValue,
+    /// The assembler could not parse the given (1-based) source line.
+    Syntax {
+        line: usize,
+    },
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    /// Data would be written past the end of memory.
+    OutOfBounds,
+    /// Two segments passed to `CPU::load_segments` share an address.
+    OverlappingSegments,
+    /// `CPU::rewind` was called without `CPU::enable_rewind`.
+    RewindDisabled,
+    /// A push would have gone below address 0 or outside memory.
+    StackOverflow,
+    /// A pop would have read past the end of memory.
+    StackUnderflow,
+    /// An instruction read memory that was neither loaded nor written, see
+    /// `CPU::enable_uninitialized_read_detection`.
+    UninitializedRead {
+        addr: u16,
+    },
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
 }
@@ -1,4 +1,11 @@
+pub mod asm;
+pub mod cpm;
 pub mod cpu;
+pub mod device;
+pub mod disasm;
 pub mod error;
+pub mod memory;
 pub mod opcode;
 pub mod register;
+
+pub use cpm::run_cpm_to_string;
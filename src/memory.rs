@@ -0,0 +1,170 @@
+//! Memory maps for boards that a flat `Vec<u8>` can't describe, e.g. ROM
+//! that ignores writes or RAM mirrored into several address windows.
+//!
+//! `CPU::with_memory` runs code out of a `Memory`: the CPU keeps a flat copy
+//! for fetches and reads, and sends each write through the map so ROM stays
+//! unchanged and every mirror of a RAM cell sees the new value.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Something the CPU can read and write bytes through.
+pub trait MemoryBus {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, val: u8);
+}
+
+/// What a range passed to `Memory::map` is backed by.
+#[derive(Debug, Clone)]
+pub enum Region {
+    /// Read/write memory.
+    Ram,
+    /// Read-only memory holding `bytes` from the start of the range; writes
+    /// are ignored and reads past the end of `bytes` give 0xFF.
+    Rom(Vec<u8>),
+    /// The same cells as the range starting at `base`, so the first address
+    /// of this range is `base`, the next `base + 1` and so on.
+    Mirror(u16),
+}
+
+/// A 64 KiB address space assembled from `Region`s. Pass it to
+/// `CPU::with_memory` to run code out of it.
+///
+/// Unmapped addresses read 0xFF and ignore writes. When ranges overlap, the
+/// one mapped last wins.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    ram: Vec<u8>,
+    regions: Vec<(RangeInclusive<u16>, Region)>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory {
+    /// An address space with nothing mapped.
+    pub fn new() -> Self {
+        Self {
+            ram: vec![0; 0x10000],
+            regions: Vec::new(),
+        }
+    }
+
+    /// Maps `range` to `region`.
+    pub fn map(&mut self, range: RangeInclusive<u16>, region: Region) {
+        self.regions.push((range, region));
+    }
+
+    /// Follows mirrors from `addr` to the region that backs it, returning the
+    /// region, its start and the resolved address. Gives up on a mirror cycle.
+    fn resolve(&self, mut addr: u16) -> Option<(&Region, u16, u16)> {
+        for _ in 0..=self.regions.len() {
+            let (range, region) = self
+                .regions
+                .iter()
+                .rev()
+                .find(|(range, _)| range.contains(&addr))?;
+            match region {
+                Region::Mirror(base) => addr = base.wrapping_add(addr - range.start()),
+                _ => return Some((region, *range.start(), addr)),
+            }
+        }
+        None
+    }
+
+    /// Where a CPU running out of this map sends its writes.
+    pub(crate) fn write_map(&self) -> WriteMap {
+        let cells: Vec<Option<u16>> = (0..=0xFFFF)
+            .map(|addr| match self.resolve(addr) {
+                Some((Region::Ram, _, cell)) => Some(cell),
+                _ => None,
+            })
+            .collect();
+        let mut aliases: HashMap<u16, Vec<u16>> = HashMap::new();
+        for (addr, cell) in cells.iter().enumerate() {
+            if let Some(cell) = cell {
+                aliases.entry(*cell).or_default().push(addr as u16);
+            }
+        }
+        aliases.retain(|_, addrs| addrs.len() > 1);
+        WriteMap { cells, aliases }
+    }
+}
+
+/// The RAM cell behind each address of a `Memory`, and the addresses that
+/// share a cell, so the CPU's flat copy can be kept in step on writes.
+#[derive(Debug, Clone)]
+pub(crate) struct WriteMap {
+    cells: Vec<Option<u16>>,
+    aliases: HashMap<u16, Vec<u16>>,
+}
+
+impl WriteMap {
+    /// The RAM cell behind `addr`, or `None` for ROM and unmapped addresses.
+    pub(crate) fn cell(&self, addr: u16) -> Option<u16> {
+        self.cells[usize::from(addr)]
+    }
+
+    /// Every address showing `cell`, if there is more than one.
+    pub(crate) fn aliases(&self, cell: u16) -> Option<&[u16]> {
+        self.aliases.get(&cell).map(Vec::as_slice)
+    }
+}
+
+impl MemoryBus for Memory {
+    fn read(&self, addr: u16) -> u8 {
+        match self.resolve(addr) {
+            Some((Region::Ram, _, addr)) => self.ram[usize::from(addr)],
+            Some((Region::Rom(bytes), start, addr)) => bytes
+                .get(usize::from(addr - start))
+                .copied()
+                .unwrap_or(0xFF),
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        if let Some((Region::Ram, _, addr)) = self.resolve(addr) {
+            self.ram[usize::from(addr)] = val;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_map() {
+        let mut mem = Memory::new();
+        mem.map(0x0000..=0x1FFF, Region::Rom(vec![0xC3, 0x00, 0x20]));
+        mem.map(0x2000..=0x3FFF, Region::Ram);
+        mem.map(0x4000..=0x5FFF, Region::Mirror(0x2000));
+
+        assert_eq!(mem.read(0x0000), 0xC3);
+        assert_eq!(mem.read(0x0003), 0xFF);
+        mem.write(0x0000, 0x00);
+        assert_eq!(mem.read(0x0000), 0xC3);
+
+        mem.write(0x2010, 0x42);
+        assert_eq!(mem.read(0x4010), 0x42);
+        mem.write(0x5FFF, 0x24);
+        assert_eq!(mem.read(0x3FFF), 0x24);
+
+        assert_eq!(mem.read(0x8000), 0xFF);
+        mem.write(0x8000, 0x00);
+        assert_eq!(mem.read(0x8000), 0xFF);
+    }
+
+    #[test]
+    fn test_mirror_cycle() {
+        let mut mem = Memory::new();
+        mem.map(0x0000..=0x00FF, Region::Mirror(0x0100));
+        mem.map(0x0100..=0x01FF, Region::Mirror(0x0000));
+        mem.write(0x0000, 0x00);
+        assert_eq!(mem.read(0x0000), 0xFF);
+    }
+}
@@ -1,10 +1,11 @@
+use crate::error::{Error, Result};
+use crate::register::Register;
 use std::convert::From;
-use std::convert::Into;
-use std::default::Default;
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Default)]
 pub enum Opcode {
+    #[default]
     NOP = 0x00,
     LXI_B = 0x01,
     STAX_B = 0x02,
@@ -288,7 +289,7 @@ impl Opcode {
 
     #[inline]
     pub fn get_src_num(self) -> u8 {
-        (self as u8 & 0b0000_0111)
+        self as u8 & 0b0000_0111
     }
 
     #[inline]
@@ -300,27 +301,162 @@ impl Opcode {
     pub fn get_rp_num_2(self) -> u8 {
         (self as u8 & 0b0011_0000) >> 4
     }
-}
 
-impl Default for Opcode {
-    fn default() -> Self {
-        Opcode::NOP
+    /// The instruction length in bytes, including its operands.
+    #[inline]
+    pub fn length(self) -> usize {
+        use Opcode::*;
+        match self {
+            LXI_B | LXI_D | LXI_H | LXI_SP | SHLD | LHLD | STA | LDA => 3,
+            JMP | JNZ | JZ | JNC | JC | JPO | JPE | JP | JM => 3,
+            CALL | CNZ | CZ | CNC | CC | CPO | CPE | CP | CM => 3,
+            MVI_B | MVI_C | MVI_D | MVI_E | MVI_H | MVI_L | MVI_M | MVI_A => 2,
+            ADI | ACI | SUI | SBI | ANI | XRI | ORI | CPI | IN | OUT => 2,
+            _ => 1,
+        }
+    }
+
+    /// T-states as `(taken, not_taken)`, from the 8080 datasheet. Only
+    /// conditional `CALL`s and `RET`s take longer when the branch is taken;
+    /// everything else, including `Jcc`, is `(n, n)`.
+    #[inline]
+    pub fn cycles(self) -> (u8, u8) {
+        let n = CYCLES[self as usize];
+        // Rcc and Ccc are 11ccc000 and 11ccc100
+        if self as u8 & 0b1100_0011 == 0b1100_0000 {
+            (n + 6, n)
+        } else {
+            (n, n)
+        }
+    }
+
+    /// The assembler mnemonic without operands, e.g. `"MOV"` for `MOV_BC`.
+    pub fn mnemonic(self) -> &'static str {
+        use Opcode::*;
+        let byte = self as u8;
+        match self {
+            HLT => "HLT",
+            _ if byte & 0b1100_0000 == 0b0100_0000 => "MOV",
+            _ if byte & 0b1100_0000 == 0b1000_0000 => {
+                ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"]
+                    [self.get_dest_num() as usize]
+            }
+            NOP => "NOP",
+            LXI_B | LXI_D | LXI_H | LXI_SP => "LXI",
+            STAX_B | STAX_D => "STAX",
+            LDAX_B | LDAX_D => "LDAX",
+            INX_B | INX_D | INX_H | INX_SP => "INX",
+            DCX_B | DCX_D | DCX_H | DCX_SP => "DCX",
+            DAD_B | DAD_D | DAD_H | DAD_SP => "DAD",
+            INR_B | INR_C | INR_D | INR_E | INR_H | INR_L | INR_M | INR_A => "INR",
+            DCR_B | DCR_C | DCR_D | DCR_E | DCR_H | DCR_L | DCR_M | DCR_A => "DCR",
+            MVI_B | MVI_C | MVI_D | MVI_E | MVI_H | MVI_L | MVI_M | MVI_A => "MVI",
+            RLC => "RLC",
+            RRC => "RRC",
+            RAL => "RAL",
+            RAR => "RAR",
+            SHLD => "SHLD",
+            LHLD => "LHLD",
+            DAA => "DAA",
+            CMA => "CMA",
+            STA => "STA",
+            LDA => "LDA",
+            STC => "STC",
+            CMC => "CMC",
+            RNZ => "RNZ",
+            RZ => "RZ",
+            RNC => "RNC",
+            RC => "RC",
+            RPO => "RPO",
+            RPE => "RPE",
+            RP => "RP",
+            RM => "RM",
+            RET => "RET",
+            JNZ => "JNZ",
+            JZ => "JZ",
+            JNC => "JNC",
+            JC => "JC",
+            JPO => "JPO",
+            JPE => "JPE",
+            JP => "JP",
+            JM => "JM",
+            JMP => "JMP",
+            CNZ => "CNZ",
+            CZ => "CZ",
+            CNC => "CNC",
+            CC => "CC",
+            CPO => "CPO",
+            CPE => "CPE",
+            CP => "CP",
+            CM => "CM",
+            CALL => "CALL",
+            POP_B | POP_D | POP_H | POP_PSW => "POP",
+            PUSH_B | PUSH_D | PUSH_H | PUSH_PSW => "PUSH",
+            RST_0 | RST_1 | RST_2 | RST_3 | RST_4 | RST_5 | RST_6 | RST_7 => "RST",
+            ADI => "ADI",
+            ACI => "ACI",
+            SUI => "SUI",
+            SBI => "SBI",
+            ANI => "ANI",
+            XRI => "XRI",
+            ORI => "ORI",
+            CPI => "CPI",
+            OUT => "OUT",
+            IN => "IN",
+            XTHL => "XTHL",
+            PCHL => "PCHL",
+            XCHG => "XCHG",
+            SPHL => "SPHL",
+            DI => "DI",
+            EI => "EI",
+            _ => unreachable!(),
+        }
+    }
+
+    /// The instruction group `self` belongs to, following the 8080 manual.
+    pub fn class(self) -> OpClass {
+        match self.mnemonic() {
+            "MOV" | "MVI" | "LXI" | "LDA" | "STA" | "LHLD" | "SHLD" | "LDAX" | "STAX" | "XCHG" => {
+                OpClass::DataTransfer
+            }
+            "ADD" | "ADC" | "ADI" | "ACI" | "SUB" | "SBB" | "SUI" | "SBI" | "INR" | "DCR"
+            | "INX" | "DCX" | "DAD" | "DAA" => OpClass::Arithmetic,
+            "ANA" | "ANI" | "XRA" | "XRI" | "ORA" | "ORI" | "CMP" | "CPI" | "RLC" | "RRC"
+            | "RAL" | "RAR" | "CMA" | "CMC" | "STC" => OpClass::Logical,
+            "PUSH" | "POP" | "XTHL" | "SPHL" => OpClass::Stack,
+            "IN" | "OUT" => OpClass::Io,
+            "EI" | "DI" | "HLT" | "NOP" => OpClass::Control,
+            // JMP, CALL, RET and their conditional forms, RST and PCHL
+            _ => OpClass::Branch,
+        }
+    }
+
+    /// Decodes a documented opcode. The undocumented bytes that `From<u8>`
+    /// runs as aliases of NOP, JMP, RET and CALL are rejected.
+    pub fn decode(n: u8) -> Result<Self> {
+        let opcode = Opcode::from(n);
+        if u8::from(opcode) == n {
+            Ok(opcode)
+        } else {
+            Err(Error::IllegalValue)
+        }
     }
 }
 
-impl Into<u8> for Opcode {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        opcode as u8
     }
 }
 
-impl Into<usize> for Opcode {
-    fn into(self) -> usize {
-        self as usize
+impl From<Opcode> for usize {
+    fn from(opcode: Opcode) -> Self {
+        opcode as usize
     }
 }
 
 impl From<u8> for Opcode {
+    #[inline]
     fn from(n: u8) -> Self {
         use Opcode::*;
         match n {
@@ -604,3 +740,265 @@ impl From<u8> for Opcode {
         }
     }
 }
+
+/// The instruction groups of the 8080 manual, with stack and I/O
+/// instructions split out of machine control. See `Opcode::class`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpClass {
+    DataTransfer,
+    Arithmetic,
+    Logical,
+    Branch,
+    Stack,
+    Io,
+    Control,
+}
+
+/// An instruction operand, as accepted by `encode`.
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Reg(Register),
+    /// The stack pointer, as in `LXI SP`.
+    Sp,
+    /// The accumulator and flags, as in `PUSH PSW`.
+    Psw,
+    Imm(u16),
+}
+
+/// T-states per opcode, from the 8080 datasheet. Conditional CALL and RET
+/// list their not-taken timing; taking them costs 6 more.
+#[rustfmt::skip]
+static CYCLES: [u8; 256] = [
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
+    4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4,
+    4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4,
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
+    7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+    5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
+    5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
+];
+
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const ALU_IMM: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+
+/// Builds the instruction bytes by OR-ing the register, pair and condition
+/// fields into the base pattern of each instruction group, the reverse of
+/// `get_dest_num`/`get_src_num`/`get_rp_num_2`.
+///
+/// `mnemonic` must be upper case. Immediates are emitted little-endian.
+pub fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Vec<u8>> {
+    encode_group(mnemonic, operands).ok_or(Error::IllegalValue)
+}
+
+fn encode_group(mnemonic: &str, operands: &[Operand]) -> Option<Vec<u8>> {
+    use Operand::*;
+
+    let reg = |r: &Register| r.clone() as u8;
+    // B, D, H and SP (or PSW for PUSH/POP) select a register pair
+    let pair = |op: &Operand, psw: bool| match op {
+        Reg(Register::B) => Some(0),
+        Reg(Register::D) => Some(1),
+        Reg(Register::H) => Some(2),
+        Sp if !psw => Some(3),
+        Psw if psw => Some(3),
+        _ => None,
+    };
+    let imm8 = |opcode: u8, val: u16| {
+        if val <= 0xFF {
+            Some(vec![opcode, val as u8])
+        } else {
+            None
+        }
+    };
+    let imm16 = |opcode: u8, val: u16| Some(vec![opcode, val as u8, (val >> 8) as u8]);
+
+    let fixed = match mnemonic {
+        "NOP" => Some(0x00),
+        "RLC" => Some(0x07),
+        "RRC" => Some(0x0F),
+        "RAL" => Some(0x17),
+        "RAR" => Some(0x1F),
+        "DAA" => Some(0x27),
+        "CMA" => Some(0x2F),
+        "STC" => Some(0x37),
+        "CMC" => Some(0x3F),
+        "HLT" => Some(0x76),
+        "RET" => Some(0xC9),
+        "XTHL" => Some(0xE3),
+        "PCHL" => Some(0xE9),
+        "XCHG" => Some(0xEB),
+        "DI" => Some(0xF3),
+        "SPHL" => Some(0xF9),
+        "EI" => Some(0xFB),
+        _ => None,
+    };
+    if let Some(opcode) = fixed {
+        return if operands.is_empty() {
+            Some(vec![opcode])
+        } else {
+            None
+        };
+    }
+
+    if let Some(i) = ALU.iter().position(|&m| m == mnemonic) {
+        return match operands {
+            [Reg(s)] => Some(vec![0x80 | (i as u8) << 3 | reg(s)]),
+            _ => None,
+        };
+    }
+    if let Some(i) = ALU_IMM.iter().position(|&m| m == mnemonic) {
+        return match operands {
+            [Imm(val)] => imm8(0xC6 | (i as u8) << 3, *val),
+            _ => None,
+        };
+    }
+
+    match (mnemonic, operands) {
+        ("MOV", [Reg(Register::Mem), Reg(Register::Mem)]) => None,
+        ("MOV", [Reg(d), Reg(s)]) => Some(vec![0x40 | reg(d) << 3 | reg(s)]),
+        ("MVI", [Reg(d), Imm(val)]) => imm8(0x06 | reg(d) << 3, *val),
+        ("INR", [Reg(d)]) => Some(vec![0x04 | reg(d) << 3]),
+        ("DCR", [Reg(d)]) => Some(vec![0x05 | reg(d) << 3]),
+        ("LXI", [rp, Imm(val)]) => imm16(0x01 | pair(rp, false)? << 4, *val),
+        ("DAD", [rp]) => Some(vec![0x09 | pair(rp, false)? << 4]),
+        ("INX", [rp]) => Some(vec![0x03 | pair(rp, false)? << 4]),
+        ("DCX", [rp]) => Some(vec![0x0B | pair(rp, false)? << 4]),
+        ("PUSH", [rp]) => Some(vec![0xC5 | pair(rp, true)? << 4]),
+        ("POP", [rp]) => Some(vec![0xC1 | pair(rp, true)? << 4]),
+        ("STAX", [rp @ Reg(Register::B)]) | ("STAX", [rp @ Reg(Register::D)]) => {
+            Some(vec![0x02 | pair(rp, false)? << 4])
+        }
+        ("LDAX", [rp @ Reg(Register::B)]) | ("LDAX", [rp @ Reg(Register::D)]) => {
+            Some(vec![0x0A | pair(rp, false)? << 4])
+        }
+        ("SHLD", [Imm(val)]) => imm16(0x22, *val),
+        ("LHLD", [Imm(val)]) => imm16(0x2A, *val),
+        ("STA", [Imm(val)]) => imm16(0x32, *val),
+        ("LDA", [Imm(val)]) => imm16(0x3A, *val),
+        ("JMP", [Imm(val)]) => imm16(0xC3, *val),
+        ("CALL", [Imm(val)]) => imm16(0xCD, *val),
+        ("OUT", [Imm(val)]) => imm8(0xD3, *val),
+        ("IN", [Imm(val)]) => imm8(0xDB, *val),
+        ("RST", [Imm(n)]) if *n < 8 => Some(vec![0xC7 | (*n as u8) << 3]),
+        _ => {
+            // `get` rather than `split_at`, which panics inside a non-ASCII character
+            let (base, cond) = match (mnemonic.get(..1), mnemonic.get(1..)) {
+                (Some("J"), Some(cond)) => (0xC2, cond),
+                (Some("C"), Some(cond)) => (0xC4, cond),
+                (Some("R"), Some(cond)) => (0xC0, cond),
+                _ => return None,
+            };
+            let cc = CONDITIONS.iter().position(|&c| c == cond)? as u8;
+            match operands {
+                [Imm(val)] if base != 0xC0 => imm16(base | cc << 3, *val),
+                [] if base == 0xC0 => Some(vec![base | cc << 3]),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        use Operand::*;
+
+        assert_eq!(
+            encode("MOV", &[Reg(Register::Acc), Reg(Register::B)]).unwrap(),
+            vec![u8::from(Opcode::MOV_AB)]
+        );
+        assert_eq!(
+            encode("LXI", &[Sp, Imm(0x1234)]).unwrap(),
+            vec![u8::from(Opcode::LXI_SP), 0x34, 0x12]
+        );
+        assert_eq!(encode("JNZ", &[Imm(0)]).unwrap()[0], u8::from(Opcode::JNZ));
+        assert!(encode("MOV", &[Reg(Register::Mem), Reg(Register::Mem)]).is_err());
+        assert!(encode("MVI", &[Reg(Register::B), Imm(0x100)]).is_err());
+        assert!(encode("FOO", &[]).is_err());
+        assert!(encode("", &[]).is_err());
+        assert!(encode("Jé", &[Imm(0)]).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Opcode::decode(0x76).unwrap(), Opcode::HLT);
+        assert_eq!(Opcode::decode(0xCD).unwrap(), Opcode::CALL);
+        for &n in [
+            0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xCB, 0xD9, 0xDD, 0xED, 0xFD,
+        ]
+        .iter()
+        {
+            assert!(Opcode::decode(n).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cycles() {
+        assert_eq!(Opcode::MOV_BC.cycles(), (5, 5));
+        assert_eq!(Opcode::MOV_BM.cycles(), (7, 7));
+        assert_eq!(Opcode::CALL.cycles(), (17, 17));
+        assert_eq!(Opcode::CNZ.cycles(), (17, 11));
+        assert_eq!(Opcode::RET.cycles(), (10, 10));
+        assert_eq!(Opcode::RM.cycles(), (11, 5));
+        assert_eq!(Opcode::JNZ.cycles(), (10, 10));
+        assert_eq!(Opcode::XTHL.cycles(), (18, 18));
+    }
+
+    #[test]
+    fn test_class() {
+        assert_eq!(Opcode::ADD_B.class(), OpClass::Arithmetic);
+        assert_eq!(Opcode::JMP.class(), OpClass::Branch);
+        assert_eq!(Opcode::RPE.class(), OpClass::Branch);
+        assert_eq!(Opcode::MOV_AM.class(), OpClass::DataTransfer);
+        assert_eq!(Opcode::CPI.class(), OpClass::Logical);
+        assert_eq!(Opcode::XTHL.class(), OpClass::Stack);
+        assert_eq!(Opcode::OUT.class(), OpClass::Io);
+        assert_eq!(Opcode::HLT.class(), OpClass::Control);
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        use Operand::*;
+
+        let mut documented = 0;
+        for b in 0..=255u8 {
+            let opcode = match Opcode::decode(b) {
+                Ok(opcode) => opcode,
+                Err(_) => continue,
+            };
+            documented += 1;
+            assert_eq!(u8::from(opcode), b, "{:?}", opcode);
+
+            // the register-field groups also round-trip through `encode`
+            let dst = || Reg(Register::from(opcode.get_dest_num() as usize));
+            let src = || Reg(Register::from(opcode.get_src_num() as usize));
+            let operands = match opcode.mnemonic() {
+                "MOV" => vec![dst(), src()],
+                "MVI" => vec![dst(), Imm(0)],
+                "INR" | "DCR" => vec![dst()],
+                "ADD" | "ADC" | "SUB" | "SBB" | "ANA" | "XRA" | "ORA" | "CMP" => vec![src()],
+                _ => continue,
+            };
+            assert_eq!(
+                encode(opcode.mnemonic(), &operands).unwrap()[0],
+                b,
+                "{:?}",
+                opcode
+            );
+        }
+        assert_eq!(documented, 244);
+    }
+}
@@ -11,31 +11,47 @@
 //! |110|A memory register|
 //! |111|The accumulator|
 
-use std::convert::Into;
+use crate::error::Error;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
 
-impl Into<usize> for Register {
-    fn into(self) -> usize {
-        self as usize
+impl From<Register> for usize {
+    fn from(reg: Register) -> Self {
+        reg as usize
     }
 }
 
-impl From<usize> for Register {
-    fn from(n: usize) -> Self {
+impl TryFrom<u8> for Register {
+    type Error = Error;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
         match n {
-            0 => Register::B,
-            1 => Register::C,
-            2 => Register::D,
-            3 => Register::E,
-            4 => Register::H,
-            5 => Register::L,
-            6 => Register::Mem,
-            7 => Register::Acc,
-            _ => unreachable!(),
+            0 => Ok(Register::B),
+            1 => Ok(Register::C),
+            2 => Ok(Register::D),
+            3 => Ok(Register::E),
+            4 => Ok(Register::H),
+            5 => Ok(Register::L),
+            6 => Ok(Register::Mem),
+            7 => Ok(Register::Acc),
+            _ => Err(Error::IllegalValue),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Panics if `n` is not a 3-bit register number; use `Register::try_from`
+/// for values that do not come from an opcode's register field.
+impl From<usize> for Register {
+    fn from(n: usize) -> Self {
+        u8::try_from(n)
+            .ok()
+            .and_then(|n| Register::try_from(n).ok())
+            .unwrap_or_else(|| panic!("{} is not a register number", n))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Register {
     B = 0b000,
     C = 0b001,
@@ -47,7 +63,82 @@ pub enum Register {
     Acc = 0b111,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Register {
+    /// The assembler name, e.g. `"M"` for `Register::Mem`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::B => "B",
+            Register::C => "C",
+            Register::D => "D",
+            Register::E => "E",
+            Register::H => "H",
+            Register::L => "L",
+            Register::Mem => "M",
+            Register::Acc => "A",
+        }
+    }
+}
+
+/// Parses an assembler register name, ignoring case.
+impl FromStr for Register {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        (0..8)
+            .map(|n| Register::from(n as usize))
+            .find(|reg| reg.name().eq_ignore_ascii_case(s))
+            .ok_or(Error::IllegalValue)
+    }
+}
+
+/// A register pair as encoded in the RP field of `LXI`, `DAD`, `INX`, `DCX`,
+/// `PUSH` and `POP`. `PUSH`/`POP` read 0b11 as PSW rather than SP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterPair {
+    BC = 0b00,
+    DE = 0b01,
+    HL = 0b10,
+    SP = 0b11,
+}
+
+/// The pair in the low two bits of `n`, e.g. an opcode's `get_rp_num_2()`.
+impl From<u8> for RegisterPair {
+    fn from(n: u8) -> Self {
+        match n & 0b11 {
+            0b00 => RegisterPair::BC,
+            0b01 => RegisterPair::DE,
+            0b10 => RegisterPair::HL,
+            _ => RegisterPair::SP,
+        }
+    }
+}
+
+impl RegisterPair {
+    /// The high and low registers of the pair, or `None` for SP.
+    pub fn registers(&self) -> Option<(Register, Register)> {
+        match self {
+            RegisterPair::BC => Some((Register::B, Register::C)),
+            RegisterPair::DE => Some((Register::D, Register::E)),
+            RegisterPair::HL => Some((Register::H, Register::L)),
+            RegisterPair::SP => None,
+        }
+    }
+}
+
+/// `PARITY[val]` is true when `val` has an even number of set bits.
+static PARITY: [bool; 256] = parity_table();
+
+const fn parity_table() -> [bool; 256] {
+    let mut table = [false; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = (i as u8).count_ones() & 1 == 0;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Flag(u8);
 
 impl Default for Flag {
@@ -56,6 +147,24 @@ impl Default for Flag {
     }
 }
 
+/// The flag byte from bit 7 down, e.g. `SZ0A0P1C` with every flag set: a
+/// flag's letter when set or `-` when clear, and the value of the unused bits.
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (bit, letter) in "SZ.A.P.C".chars().enumerate() {
+            let set = self.0 & (0x80 >> bit) != 0;
+            let c = match letter {
+                '.' if set => '1',
+                '.' => '0',
+                _ if set => letter,
+                _ => '-',
+            };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
 impl Flag {
     #[inline]
     pub fn value(&self) -> u8 {
@@ -72,6 +181,42 @@ impl Flag {
         Self(b)
     }
 
+    /// The parity flag for `val`: true when it has an even number of set bits.
+    #[inline]
+    pub fn parity_of(val: u8) -> bool {
+        PARITY[val as usize]
+    }
+
+    /// Whether S, Z, AC, P and CY all match, ignoring the constant bits 1, 3
+    /// and 5.
+    #[inline]
+    pub fn eq_significant(&self, other: &Flag) -> bool {
+        (self.0 ^ other.0) & 0b1101_0101 == 0
+    }
+
+    /// The five flags in the order S, Z, AC, P, CY.
+    pub fn to_bools(&self) -> [bool; 5] {
+        [
+            self.sign_flag(),
+            self.zero_flag(),
+            self.auxiliary_flag(),
+            self.parity_flag(),
+            self.carry_flag(),
+        ]
+    }
+
+    /// The inverse of `to_bools`, with the constant bits as after reset.
+    pub fn from_bools(flags: [bool; 5]) -> Self {
+        let [sign, zero, aux, parity, carry] = flags;
+        let mut flag = Self::default();
+        flag.set_sign_flag(sign);
+        flag.set_zero_flag(zero);
+        flag.set_auxiliary_carry_flag(aux);
+        flag.set_parity_flag(parity);
+        flag.set_carry_flag(carry);
+        flag
+    }
+
     #[inline]
     pub fn set_carry_flag(&mut self, b: bool) {
         if b {
@@ -138,7 +283,75 @@ impl Flag {
     }
 
     #[inline]
-    pub fn sign_flag(&mut self) -> bool {
+    pub fn sign_flag(&self) -> bool {
         self.0 & 0b1000_0000 == (1 << 7)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_try_from() {
+        assert!(matches!(Register::try_from(6u8), Ok(Register::Mem)));
+        assert!(matches!(Register::try_from(9u8), Err(Error::IllegalValue)));
+        assert!(matches!(Register::from(7usize), Register::Acc));
+    }
+
+    #[test]
+    fn test_register_name() {
+        assert_eq!(Register::from_str("H").unwrap().name(), "H");
+        assert_eq!("m".parse::<Register>().unwrap().name(), "M");
+        assert!("SP".parse::<Register>().is_err());
+    }
+
+    #[test]
+    fn test_flag_bools() {
+        for bits in 0..32u8 {
+            let bools = [
+                bits & 0b10000 != 0,
+                bits & 0b01000 != 0,
+                bits & 0b00100 != 0,
+                bits & 0b00010 != 0,
+                bits & 0b00001 != 0,
+            ];
+            let flag = Flag::from_bools(bools);
+            assert_eq!(flag.to_bools(), bools);
+            assert_eq!(flag.value() & 0b0010_1010, 0b0000_0010);
+        }
+    }
+
+    #[test]
+    fn test_parity_of() {
+        for val in 0..=255u8 {
+            assert_eq!(Flag::parity_of(val), val.count_ones() % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn test_register_pair() {
+        assert_eq!(RegisterPair::from(0b10), RegisterPair::HL);
+        assert_eq!(RegisterPair::from(0b111), RegisterPair::SP);
+        assert!(matches!(
+            RegisterPair::DE.registers(),
+            Some((Register::D, Register::E))
+        ));
+        assert!(RegisterPair::SP.registers().is_none());
+    }
+
+    #[test]
+    fn test_flag_display() {
+        assert_eq!(Flag::default().to_string(), "--0-0-1-");
+        assert_eq!(Flag::new(0xD7).to_string(), "SZ0A0P1C");
+        assert_eq!(Flag::new(0b1010_1001).to_string(), "S-1-1-0C");
+    }
+
+    #[test]
+    fn test_flag_eq_significant() {
+        assert!(Flag::new(0b0000_0010).eq_significant(&Flag::new(0b0000_1010)));
+        assert!(Flag::new(0b1101_0111).eq_significant(&Flag::new(0b1111_0101)));
+        assert!(!Flag::new(0b0000_0010).eq_significant(&Flag::new(0b0001_0010)));
+        assert!(!Flag::new(0b0000_0011).eq_significant(&Flag::new(0b0000_0010)));
+    }
+}